@@ -0,0 +1,311 @@
+use {
+    jiff::Timestamp,
+    std::{
+        fmt::Write as _,
+        fs::{File, OpenOptions},
+        io::{self, Write as _},
+        net::IpAddr,
+        path::{Path, PathBuf},
+        pin::Pin,
+        str::FromStr,
+        sync::Mutex,
+        task::{Context, Poll, ready},
+        time::{Duration, Instant},
+    },
+    tokio::io::{AsyncRead, AsyncWrite, ReadBuf},
+};
+
+/// How entries in the access log are written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum Format {
+    /// The space separated format agate has always used.
+    #[default]
+    Agate,
+    /// One JSON object per line.
+    Json,
+    /// Like the Common Log Format of web servers.
+    Common,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "agate" => Ok(Self::Agate),
+            "json" => Ok(Self::Json),
+            "common" => Ok(Self::Common),
+            _ => Err(format!(
+                "unknown access log format {s:?}, expected one of agate, json or common"
+            )),
+        }
+    }
+}
+
+/// What happened to a connection.
+#[derive(Debug)]
+pub(crate) struct Entry {
+    pub time: Timestamp,
+    start: Instant,
+    /// The local address or Unix socket the connection was received on.
+    pub listener: String,
+    /// The IP address of the client, if it should be logged.
+    pub peer: Option<IpAddr>,
+    /// The hostname the client sent in the TLS handshake.
+    pub sni: Option<String>,
+    /// The request line as received, with any Titan token redacted.
+    pub request: Option<String>,
+    /// The response status, or one of the special codes 00 (TLS error), 01
+    /// (IP error) and 02 (timeout) if no response was sent.
+    pub status: Option<u8>,
+    pub meta: Option<String>,
+    /// The number of bytes sent to the client, including the header.
+    pub bytes_sent: u64,
+    /// The SHA-256 fingerprint of the client certificate.
+    pub cert: Option<String>,
+    pub error: Option<String>,
+}
+
+impl Entry {
+    pub fn new(listener: String, peer: Option<IpAddr>) -> Self {
+        Self {
+            time: Timestamp::now(),
+            start: Instant::now(),
+            listener,
+            peer,
+            sni: None,
+            request: None,
+            status: None,
+            meta: None,
+            bytes_sent: 0,
+            cert: None,
+            error: None,
+        }
+    }
+
+    /// Records that no response could be sent, using one of the special
+    /// status codes.
+    pub fn failed(mut self, status: u8, meta: &str, error: impl ToString) -> Self {
+        self.status = Some(status);
+        self.meta = Some(meta.into());
+        self.error = Some(error.to_string());
+        self
+    }
+
+    /// The time since the connection was accepted.
+    pub fn duration(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    pub fn format(&self, format: Format) -> String {
+        match format {
+            Format::Agate => self.format_agate(),
+            Format::Json => self.format_json(),
+            Format::Common => self.format_common(),
+        }
+    }
+
+    fn format_agate(&self) -> String {
+        let mut line = format!("{} ", self.listener);
+        match self.peer {
+            Some(peer) => write!(line, "{peer}").unwrap(),
+            // Do not log IP address, but something else so columns still line up.
+            None => line.push('-'),
+        }
+        // log literal request (might be different from or not an actual URL)
+        write!(
+            line,
+            " \"{}\"",
+            self.request.as_deref().unwrap_or_default().escape_default()
+        )
+        .unwrap();
+        if let Some(status) = self.status {
+            write!(
+                line,
+                " {status:02} \"{}\"",
+                self.meta.as_deref().unwrap_or_default()
+            )
+            .unwrap();
+        }
+        if let Some(cert) = &self.cert {
+            write!(line, " cert:{cert}").unwrap();
+        }
+        if let Some(error) = &self.error {
+            write!(line, " error:{error}").unwrap();
+        }
+        line
+    }
+
+    fn format_json(&self) -> String {
+        let string = |s: Option<&str>| s.map_or_else(|| "null".into(), json_string);
+        let peer = self.peer.map(|peer| peer.to_string());
+        format!(
+            concat!(
+                "{{\"time\":\"{:.3}\",\"listener\":{},\"peer\":{},\"sni\":{},",
+                "\"request\":{},\"status\":{},\"meta\":{},\"bytes_sent\":{},",
+                "\"duration\":{:.6},\"cert\":{},\"error\":{}}}"
+            ),
+            self.time,
+            json_string(&self.listener),
+            string(peer.as_deref()),
+            string(self.sni.as_deref()),
+            string(self.request.as_deref()),
+            self.status
+                .map_or_else(|| "null".into(), |status| status.to_string()),
+            string(self.meta.as_deref()),
+            self.bytes_sent,
+            self.duration().as_secs_f64(),
+            string(self.cert.as_deref()),
+            string(self.error.as_deref()),
+        )
+    }
+
+    /// Formats the entry like `host ident user [time] "request" status bytes`,
+    /// with the certificate fingerprint as the user.
+    fn format_common(&self) -> String {
+        let dash = |s: Option<String>| s.unwrap_or_else(|| "-".into());
+        format!(
+            "{} - {} [{}] \"{}\" {} {}",
+            dash(self.peer.map(|peer| peer.to_string())),
+            dash(self.cert.clone()),
+            self.time.strftime("%d/%b/%Y:%H:%M:%S +0000"),
+            self.request.as_deref().unwrap_or_default().escape_default(),
+            dash(self.status.map(|status| format!("{status:02}"))),
+            self.bytes_sent,
+        )
+    }
+}
+
+/// Quotes and escapes a string for JSON.
+fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => write!(json, "\\u{:04x}", c as u32).unwrap(),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// Where the access log is written to.
+#[derive(Debug)]
+pub(crate) struct AccessLog {
+    format: Format,
+    /// The file to append entries to. `None` means the normal log, `-` means
+    /// standard output.
+    path: Option<PathBuf>,
+    file: Mutex<Option<File>>,
+}
+
+impl AccessLog {
+    pub fn new(format: Format, path: Option<PathBuf>) -> Result<Self, String> {
+        let file = match &path {
+            Some(path) if path.as_os_str() != "-" => Some(
+                open(path)
+                    .map_err(|e| format!("could not open access log {}: {e}", path.display()))?,
+            ),
+            _ => None,
+        };
+        Ok(Self {
+            format,
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn write(&self, entry: &Entry) {
+        let line = entry.format(self.format);
+        let mut file = self.file.lock().unwrap();
+        let result = match (&self.path, file.as_mut()) {
+            (Some(_), Some(file)) => writeln!(file, "{line}"),
+            (Some(_), None) => writeln!(io::stdout().lock(), "{line}"),
+            // mixed in with other messages, so failed requests stand out
+            (None, _) if entry.error.is_some() => {
+                log::warn!("{line}");
+                Ok(())
+            }
+            (None, _) => {
+                log::info!("{line}");
+                Ok(())
+            }
+        };
+        if let Err(e) = result {
+            log::error!("Could not write to access log: {e}");
+        }
+    }
+
+    /// Opens the file again, so a log file that was moved away by log rotation
+    /// is replaced with a new one.
+    pub fn reopen(&self) {
+        let Some(path) = self.path.as_ref().filter(|path| path.as_os_str() != "-") else {
+            return;
+        };
+        match open(path) {
+            Ok(new) => *self.file.lock().unwrap() = Some(new),
+            Err(e) => log::error!("Could not reopen access log {}: {e}", path.display()),
+        }
+    }
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Wraps a stream to count the bytes written to it.
+#[derive(Debug)]
+pub(crate) struct CountWrites<S> {
+    inner: S,
+    written: u64,
+}
+
+impl<S> CountWrites<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner, written: 0 }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CountWrites<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CountWrites<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let written = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.written += written as u64;
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,175 @@
+use std::{
+    collections::HashMap,
+    fs::Metadata,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering::Relaxed},
+    },
+    time::SystemTime,
+};
+
+/// What is cached for a path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Kind {
+    /// The contents of a file.
+    File,
+    /// The listing of a directory, without the preamble.
+    Listing,
+}
+
+/// Cached data for a path.
+#[derive(Debug, Clone)]
+pub(crate) struct Cached {
+    pub data: Arc<[u8]>,
+    /// The MIME type with its parameters from the sidecar files, as it is
+    /// sent in the response header.
+    pub header: Arc<str>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    cached: Cached,
+    /// The modification time and size of the file when it was read.
+    modified: SystemTime,
+    len: u64,
+    /// When the file was read, modifications at the same time may not have
+    /// been read.
+    read_at: SystemTime,
+    /// The version of the sidecar files the header was taken from.
+    version: u64,
+    /// For evicting the least recently used entry.
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    map: HashMap<(Kind, PathBuf), Entry>,
+    bytes: usize,
+    /// Counts up with every use of an entry.
+    clock: u64,
+}
+
+/// A cache of small files and directory listings, which are checked for
+/// changes by their modification time and size.
+#[derive(Debug)]
+pub(crate) struct Cache {
+    max_bytes: usize,
+    max_entries: usize,
+    /// Larger files are not cached.
+    pub max_file_size: u64,
+    entries: Mutex<Entries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Cache {
+    pub fn new(max_bytes: usize, max_entries: usize, max_file_size: u64) -> Self {
+        Self {
+            max_bytes,
+            max_entries,
+            max_file_size,
+            entries: Mutex::default(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the cached data for the path, if it was not changed since it
+    /// was read according to the current `metadata` of the path and the
+    /// `version` of the sidecar files is the same.
+    pub fn get(
+        &self,
+        kind: Kind,
+        path: &Path,
+        metadata: &Metadata,
+        version: u64,
+    ) -> Option<Cached> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.clock += 1;
+        let clock = entries.clock;
+
+        let key = (kind, path.to_path_buf());
+        let cached = match entries.map.get_mut(&key) {
+            Some(entry) if entry.is_current(metadata) && entry.version == version => {
+                entry.last_used = clock;
+                Some(entry.cached.clone())
+            }
+            Some(_) => {
+                let entry = entries.map.remove(&key).unwrap();
+                entries.bytes -= entry.cached.data.len();
+                None
+            }
+            None => None,
+        };
+        drop(entries);
+
+        let (counter, result) = match cached {
+            Some(_) => (&self.hits, "hit"),
+            None => (&self.misses, "miss"),
+        };
+        counter.fetch_add(1, Relaxed);
+        log::debug!(
+            "Cache {result} for {path:?} ({} hits, {} misses)",
+            self.hits.load(Relaxed),
+            self.misses.load(Relaxed),
+        );
+        cached
+    }
+
+    /// Stores data for the path that was read at `read_at`, when the path
+    /// had the `metadata` and the sidecar files had the `version`.
+    pub fn insert(
+        &self,
+        kind: Kind,
+        path: &Path,
+        metadata: &Metadata,
+        read_at: SystemTime,
+        version: u64,
+        cached: Cached,
+    ) {
+        let Ok(modified) = metadata.modified() else {
+            // changes could not be detected
+            return;
+        };
+        let size = cached.data.len();
+        if size > self.max_bytes || self.max_entries == 0 {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.clock += 1;
+        let entry = Entry {
+            cached,
+            modified,
+            len: metadata.len(),
+            read_at,
+            version,
+            last_used: entries.clock,
+        };
+        if let Some(old) = entries.map.insert((kind, path.to_path_buf()), entry) {
+            entries.bytes -= old.cached.data.len();
+        }
+        entries.bytes += size;
+
+        while entries.bytes > self.max_bytes || entries.map.len() > self.max_entries {
+            let oldest = entries
+                .map
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+                .unwrap();
+            let entry = entries.map.remove(&oldest).unwrap();
+            entries.bytes -= entry.cached.data.len();
+        }
+    }
+}
+
+impl Entry {
+    fn is_current(&self, metadata: &Metadata) -> bool {
+        metadata.len() == self.len
+            && metadata
+                .modified()
+                .is_ok_and(|modified| modified == self.modified && modified < self.read_at)
+    }
+}
@@ -0,0 +1,208 @@
+use {
+    crate::certificates,
+    rcgen::{CertificateParams, DnType, KeyPair, SanType},
+    std::{
+        error::Error,
+        fs::{self, File},
+        io::Write,
+        net::IpAddr,
+        path::Path,
+        str::FromStr,
+        time::{Duration, SystemTime},
+    },
+    x509_parser::time::ASN1Time,
+};
+
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+
+type Result<T = (), E = Box<dyn Error + Send + Sync>> = std::result::Result<T, E>;
+
+/// The key algorithms that can be used for generated certificates.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum KeyAlgorithm {
+    EcdsaP256,
+    EcdsaP384,
+    Ed25519,
+    /// RSA with the given key size in bits, for old clients that do not
+    /// support the other algorithms.
+    Rsa(usize),
+}
+
+impl FromStr for KeyAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ecdsa" | "ecdsa-p256" => Ok(Self::EcdsaP256),
+            "ecdsa-p384" => Ok(Self::EcdsaP384),
+            "ed25519" => Ok(Self::Ed25519),
+            "rsa" | "rsa2048" => Ok(Self::Rsa(2048)),
+            "rsa3072" => Ok(Self::Rsa(3072)),
+            "rsa4096" => Ok(Self::Rsa(4096)),
+            _ => Err(format!(
+                "unknown key algorithm {s:?}, expected one of ecdsa, ecdsa-p384, ed25519, rsa, rsa3072 or rsa4096"
+            )),
+        }
+    }
+}
+
+impl KeyAlgorithm {
+    fn generate(self) -> Result<KeyPair> {
+        match self {
+            Self::EcdsaP256 => Ok(KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256)?),
+            Self::EcdsaP384 => Ok(KeyPair::generate_for(&rcgen::PKCS_ECDSA_P384_SHA384)?),
+            Self::Ed25519 => Ok(KeyPair::generate_for(&rcgen::PKCS_ED25519)?),
+            Self::Rsa(bits) => {
+                // ring can not generate RSA keys
+                use rsa::{pkcs8::EncodePrivateKey, rand_core::OsRng};
+
+                let key = rsa::RsaPrivateKey::new(&mut OsRng, bits)?;
+                let der = key.to_pkcs8_der()?;
+                Ok(KeyPair::from_pkcs8_der_and_sign_algo(
+                    &der.as_bytes().into(),
+                    &rcgen::PKCS_RSA_SHA256,
+                )?)
+            }
+        }
+    }
+}
+
+/// Parses subject fields in the format `KEY=VALUE,KEY=VALUE`, where the keys
+/// are `CN`, `O`, `OU`, `L`, `ST` or `C`.
+pub(crate) fn parse_subject(s: &str) -> Result<Vec<(DnType, String)>, String> {
+    s.split(',')
+        .map(|field| {
+            let (key, value) = field
+                .split_once('=')
+                .ok_or_else(|| format!("subject field {field:?} is not of the form KEY=VALUE"))?;
+            let dn_type = match key.trim() {
+                "CN" => DnType::CommonName,
+                "O" => DnType::OrganizationName,
+                "OU" => DnType::OrganizationalUnitName,
+                "L" => DnType::LocalityName,
+                "ST" => DnType::StateOrProvinceName,
+                "C" => DnType::CountryName,
+                key => return Err(format!("unknown subject field {key:?}")),
+            };
+            Ok((dn_type, value.trim().to_string()))
+        })
+        .collect()
+}
+
+/// The settings for generating self signed certificates.
+#[derive(Debug)]
+pub(crate) struct Generator {
+    pub algorithm: KeyAlgorithm,
+    /// How long generated certificates are valid, if `None` they are valid
+    /// until 4096-01-01.
+    pub validity: Option<Duration>,
+    /// Additional subject alternative names. If a hostname is given, the name
+    /// is only added to the certificate for that hostname.
+    pub extra_names: Vec<(Option<String>, String)>,
+    /// Fields of the subject, in addition to or replacing the common name.
+    pub subject: Vec<(DnType, String)>,
+}
+
+impl Generator {
+    fn params(&self, domain: &str) -> Result<CertificateParams> {
+        let mut cert_params = CertificateParams::new(vec![domain.to_string()])?;
+        for (_, name) in self
+            .extra_names
+            .iter()
+            .filter(|(hostname, _)| hostname.as_deref().is_none_or(|h| h == domain))
+        {
+            cert_params
+                .subject_alt_names
+                .push(match name.parse::<IpAddr>() {
+                    Ok(ip) => SanType::IpAddress(ip),
+                    Err(_) => SanType::DnsName(name.as_str().try_into()?),
+                });
+        }
+
+        cert_params
+            .distinguished_name
+            .push(DnType::CommonName, domain);
+        for (dn_type, value) in &self.subject {
+            cert_params
+                .distinguished_name
+                .push(dn_type.clone(), value.as_str());
+        }
+
+        if let Some(validity) = self.validity {
+            let now = SystemTime::now();
+            cert_params.not_before = now.into();
+            cert_params.not_after = (now + validity).into();
+        }
+        // otherwise <CertificateParams as Default>::default() already
+        // implements a date in the far future from the time of writing:
+        // 4096-01-01
+
+        Ok(cert_params)
+    }
+
+    /// Generates a new key and a self signed certificate for the domain and
+    /// stores them in the directory for the domain in the certificate
+    /// directory.
+    pub fn generate(&self, certs_dir: &Path, domain: &str) -> Result {
+        let key_pair = self.algorithm.generate()?;
+
+        // generate the certificate with the configuration
+        let cert = self.params(domain)?.self_signed(&key_pair)?;
+
+        // make sure the certificate directory exists
+        let cert_dir = certs_dir.join(domain);
+        fs::create_dir(&cert_dir)?;
+
+        // write certificate data to disk
+        let mut cert_file = File::create(cert_dir.join(certificates::CERT_FILE_NAME))?;
+        cert_file.write_all(cert.der())?;
+
+        // write key data to disk
+        let key_file_path = cert_dir.join(certificates::KEY_FILE_NAME);
+        let mut key_file = File::create(&key_file_path)?;
+        #[cfg(unix)]
+        {
+            // set permissions so only owner can read
+            match key_file.set_permissions(fs::Permissions::from_mode(0o400)) {
+                Ok(_) => (),
+                Err(_) => log::warn!(
+                    "could not set permissions for new key file {}",
+                    key_file_path.display()
+                ),
+            }
+        }
+        key_file.write_all(key_pair.serialized_der())?;
+
+        Ok(())
+    }
+
+    /// If the certificate for the domain is a self signed certificate that
+    /// has expired, replaces it with a new certificate for the same key, so
+    /// clients that trust the key on first use will continue to work. Returns
+    /// whether the certificate was replaced.
+    pub fn regenerate_if_expired(&self, certs_dir: &Path, domain: &str) -> Result<bool> {
+        let cert_dir = certs_dir.join(domain);
+        let cert_file_path = cert_dir.join(certificates::CERT_FILE_NAME);
+        let (Ok(cert), Ok(key)) = (
+            fs::read(&cert_file_path),
+            fs::read(cert_dir.join(certificates::KEY_FILE_NAME)),
+        ) else {
+            // not a certificate generated by agate
+            return Ok(false);
+        };
+
+        let (_, parsed) = x509_parser::parse_x509_certificate(&cert)?;
+        let expired = parsed.validity().not_after < ASN1Time::now();
+        if parsed.issuer() != parsed.subject() || !expired {
+            return Ok(false);
+        }
+
+        log::info!("The certificate for {domain} has expired, generating a new one.");
+        let key_pair = KeyPair::try_from(key.as_slice())?;
+        let cert = self.params(domain)?.self_signed(&key_pair)?;
+        fs::write(cert_file_path, cert.der())?;
+
+        Ok(true)
+    }
+}
@@ -1,30 +1,234 @@
 use {
+    configparser::ini::Ini,
     std::{
         ffi::OsStr,
         fmt::{Display, Formatter},
-        path::Path,
-        sync::Arc,
+        path::{Path, PathBuf},
+        sync::{
+            Arc, RwLock,
+            atomic::{AtomicBool, Ordering},
+        },
+        time::{Duration, SystemTime},
     },
     tokio_rustls::rustls::{
-        self,
-        crypto::ring::sign::any_supported_type,
-        pki_types::{self, CertificateDer, PrivateKeyDer},
-        server::{ClientHello, ResolvesServerCert},
+        self, DigitallySignedStruct, DistinguishedName, SignatureScheme,
+        client::danger::HandshakeSignatureValid,
+        crypto::{
+            WebPkiSupportedAlgorithms,
+            ring::{default_provider, sign::any_supported_type},
+            verify_tls12_signature, verify_tls13_signature,
+        },
+        pki_types::{self, CertificateDer, PrivateKeyDer, UnixTime, pem::PemObject},
+        server::{
+            ClientHello, ResolvesServerCert,
+            danger::{ClientCertVerified, ClientCertVerifier},
+        },
         sign::{CertifiedKey, SigningKey},
     },
+    x509_parser::time::ASN1Time,
 };
 
 /// A struct that holds all loaded certificates and the respective domain
 /// names.
 #[derive(Debug)]
 pub(crate) struct CertStore {
-    /// Stores the certificates and the domains they apply to, sorted by domain
-    /// names, longest matches first
-    certs: Vec<(String, Arc<CertifiedKey>)>,
+    /// Stores the certificates and the names they apply to.
+    certs: Vec<(NamePattern, Arc<CertifiedKey>)>,
+    /// The certificate and key files referenced by the mapping file, if any.
+    /// They may be outside the certificate directory.
+    files: Vec<PathBuf>,
+    /// Details about each loaded certificate.
+    infos: Vec<CertInfo>,
+}
+
+/// How long before a certificate expires warnings are logged about it.
+const EXPIRY_WARNING: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+
+/// Details about a loaded certificate, for logging and reporting.
+#[derive(Debug)]
+pub(crate) struct CertInfo {
+    /// The names the certificate is used for.
+    names: Vec<String>,
+    subject: String,
+    /// The DNS names from the subject alternative names.
+    dns_names: Vec<String>,
+    /// The validity period, `None` if the certificate could not be parsed.
+    validity: Option<(ASN1Time, ASN1Time)>,
+    fingerprint: String,
+}
+
+impl CertInfo {
+    fn new(names: Vec<String>, cert: &CertificateDer<'_>) -> Self {
+        let parsed = x509_parser::parse_x509_certificate(cert).ok();
+        Self {
+            names,
+            subject: parsed
+                .as_ref()
+                .map_or_else(String::new, |(_, cert)| cert.subject().to_string()),
+            dns_names: dns_names(cert),
+            validity: parsed.map(|(_, cert)| {
+                let validity = cert.validity();
+                (validity.not_before, validity.not_after)
+            }),
+            fingerprint: fingerprint(cert),
+        }
+    }
+
+    /// Describes what is wrong with the certificate, if anything: it is not
+    /// yet valid, expired or expires soon.
+    pub fn problem(&self) -> Option<String> {
+        let Some((not_before, not_after)) = self.validity else {
+            return Some("could not be parsed".to_string());
+        };
+        let now = ASN1Time::now().timestamp();
+        let remaining = not_after.timestamp() - now;
+
+        if not_before.timestamp() > now {
+            Some(format!("is not valid before {not_before}"))
+        } else if remaining < 0 {
+            Some(format!("expired on {not_after}"))
+        } else if remaining < EXPIRY_WARNING.as_secs() as i64 {
+            Some(format!(
+                "expires on {not_after}, in {} days",
+                remaining / (24 * 60 * 60)
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// The names the certificate is used for, for messages.
+    pub fn names(&self) -> String {
+        self.names.join(", ")
+    }
+}
+
+impl Display for CertInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: subject \"{}\", alternative names [{}], ",
+            self.names(),
+            self.subject,
+            self.dns_names.join(", ")
+        )?;
+        if let Some((not_before, not_after)) = self.validity {
+            write!(f, "valid from {not_before} until {not_after}, ")?;
+        }
+        write!(f, "fingerprint {}", self.fingerprint)
+    }
+}
+
+/// The server names a certificate is used for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum NamePattern {
+    /// Any name, also used for clients that do not send a server name.
+    Fallback,
+    /// The domain and all its subdomains, from a domain directory.
+    Subtree(String),
+    /// Exactly this domain, from the mapping file.
+    Exact(String),
+    /// The subdomains one label below this domain, from a pattern like
+    /// `*.example.com` in the mapping file.
+    Wildcard(String),
+}
+
+impl NamePattern {
+    /// Parses a pattern from the mapping file.
+    fn parse(pattern: &str) -> Result<Self, String> {
+        let pattern = normalize_name(pattern);
+        match pattern.strip_prefix("*.") {
+            _ if pattern == "*" => Ok(Self::Fallback),
+            Some(domain) if !domain.is_empty() && !domain.contains('*') => {
+                Ok(Self::Wildcard(domain.to_string()))
+            }
+            None if !pattern.is_empty() && !pattern.contains('*') => Ok(Self::Exact(pattern)),
+            _ => Err(format!("{pattern:?} is not a valid name pattern")),
+        }
+    }
+
+    /// Checks if the name matches this pattern, comparing whole labels only.
+    /// If it matches, returns how specific the match is so the best matching
+    /// certificate can be found: more matching labels are better and an exact
+    /// match is better than a wildcard match, which is better than a matching
+    /// parent domain.
+    fn matches(&self, name: &str) -> Option<(usize, u8)> {
+        let labels = |domain: &str| domain.split('.').count();
+        match self {
+            Self::Fallback => Some((0, 0)),
+            Self::Subtree(domain) => (name == domain
+                || name.strip_suffix(domain.as_str())?.ends_with('.'))
+            .then(|| (labels(domain), 0)),
+            Self::Exact(domain) => (name == domain).then(|| (labels(domain), 2)),
+            Self::Wildcard(domain) => {
+                let label = name.strip_suffix(domain.as_str())?.strip_suffix('.')?;
+                (!label.is_empty() && !label.contains('.')).then(|| (labels(domain) + 1, 1))
+            }
+        }
+    }
+
+    /// Checks if the DNS names from a certificate's subject alternative names
+    /// cover all names matching this pattern.
+    fn covered_by(&self, dns_names: &[String]) -> bool {
+        match self {
+            Self::Fallback => true,
+            Self::Subtree(domain) | Self::Exact(domain) => dns_names.iter().any(|dns_name| {
+                dns_name == domain
+                    || dns_name
+                        .strip_prefix("*.")
+                        .and_then(|parent| Self::Wildcard(parent.to_string()).matches(domain))
+                        .is_some()
+            }),
+            Self::Wildcard(domain) => dns_names.iter().any(|dns_name| {
+                dns_name
+                    .strip_prefix("*.")
+                    .is_some_and(|parent| parent == domain)
+            }),
+        }
+    }
+}
+
+impl Display for NamePattern {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Fallback => write!(f, "*"),
+            Self::Subtree(domain) | Self::Exact(domain) => write!(f, "{domain}"),
+            Self::Wildcard(domain) => write!(f, "*.{domain}"),
+        }
+    }
+}
+
+/// Domain names are compared case insensitively and without a trailing dot.
+fn normalize_name(name: &str) -> String {
+    name.trim().trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Returns the DNS names in the subject alternative names of the certificate.
+fn dns_names(cert: &CertificateDer<'_>) -> Vec<String> {
+    use x509_parser::extensions::GeneralName;
+
+    let Ok((_, cert)) = x509_parser::parse_x509_certificate(cert) else {
+        return vec![];
+    };
+    let Ok(Some(san)) = cert.subject_alternative_name() else {
+        return vec![];
+    };
+    san.value
+        .general_names
+        .iter()
+        .filter_map(|name| match name {
+            GeneralName::DNSName(name) => Some(normalize_name(name)),
+            _ => None,
+        })
+        .collect()
 }
 
 pub static CERT_FILE_NAME: &str = "cert.der";
 pub static KEY_FILE_NAME: &str = "key.der";
+pub static CERT_PEM_FILE_NAME: &str = "cert.pem";
+pub static CHAIN_PEM_FILE_NAME: &str = "fullchain.pem";
+pub static KEY_PEM_FILE_NAME: &str = "key.pem";
+pub static MAPPING_FILE_NAME: &str = "mapping.ini";
 
 #[derive(Debug)]
 pub enum CertLoadError {
@@ -44,6 +248,24 @@ pub enum CertLoadError {
     /// neither a key file nor a certificate file were present for the given
     /// domain (but a folder was present)
     EmptyDomain(String),
+    /// the key file for the specified domain does not belong to the
+    /// certificate
+    KeyMismatch(String),
+    /// there is no certificate for one of the configured hostnames
+    MissingDomain(String),
+    /// a PEM file for the specified domain could not be parsed
+    BadPem(String, String, String),
+    /// there are multiple certificate files or multiple key files for the
+    /// specified domain, so it is unclear which one should be used
+    Ambiguous(String, &'static str, &'static str),
+    /// the certificate and key files for the specified domain are not in the
+    /// same format
+    MixedFormats(String),
+    /// the mapping file is invalid
+    BadMapping(String),
+    /// the certificate for the specified mapping file section does not
+    /// include the name it is mapped to in its subject alternative names
+    NameNotCovered(String, String),
 }
 
 impl Display for CertLoadError {
@@ -65,44 +287,158 @@ impl Display for CertLoadError {
                 f,
                 "A folder for {domain} exists, but there is no certificate or key file."
             ),
+            Self::KeyMismatch(domain) => write!(
+                f,
+                "The key file for {domain} does not belong to the certificate."
+            ),
+            Self::MissingDomain(domain) => write!(f, "There is no certificate for {domain}."),
+            Self::BadPem(domain, file, err) => {
+                write!(f, "The file {file} for {domain} is malformed: {err}")
+            }
+            Self::Ambiguous(domain, a, b) => write!(
+                f,
+                "Both {a} and {b} exist for {domain}, but only one of them may be present."
+            ),
+            Self::MixedFormats(domain) => write!(
+                f,
+                "The certificate and key files for {domain} must either both be DER files or both be PEM files."
+            ),
+            Self::BadMapping(err) => write!(f, "The certificate mapping file is invalid: {err}"),
+            Self::NameNotCovered(section, name) => write!(
+                f,
+                "The certificate for {section} is mapped to {name}, but its subject alternative names do not include it."
+            ),
         }
     }
 }
 
 impl std::error::Error for CertLoadError {}
 
+/// Computes the SHA-256 fingerprint of a DER encoded certificate, formatted as
+/// lowercase hexadecimal digits without separators.
+pub(crate) fn fingerprint(cert: &CertificateDer<'_>) -> String {
+    ring::digest::digest(&ring::digest::SHA256, cert)
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Returns which of the given files exists in the directory. It is an error
+/// if more than one of them exists.
+fn find_file(
+    dir: &Path,
+    name: &str,
+    candidates: &[&'static str],
+) -> Result<Option<&'static str>, CertLoadError> {
+    let mut found = candidates.iter().filter(|file| dir.join(file).is_file());
+    match (found.next(), found.next()) {
+        (Some(a), Some(b)) => Err(CertLoadError::Ambiguous(name.to_string(), a, b)),
+        (file, _) => Ok(file.copied()),
+    }
+}
+
+/// Loads the certificate (chain) and key from the directory for the domain.
+/// The empty string as the domain means the fallback certificate directly in
+/// the certificate directory.
 fn load_domain(certs_dir: &Path, domain: String) -> Result<CertifiedKey, CertLoadError> {
-    let mut path = certs_dir.to_path_buf();
-    path.push(&domain);
-    // load certificate from file
-    path.push(CERT_FILE_NAME);
-    if !path.is_file() {
-        return Err(if !path.with_file_name(KEY_FILE_NAME).is_file() {
-            CertLoadError::EmptyDomain(domain)
-        } else {
-            CertLoadError::MissingCert(domain)
-        });
+    let dir = certs_dir.join(&domain);
+    // used for error messages
+    let name = if domain.is_empty() {
+        "fallback".to_string()
+    } else {
+        domain
+    };
+
+    let cert_file = find_file(
+        &dir,
+        &name,
+        &[CERT_FILE_NAME, CERT_PEM_FILE_NAME, CHAIN_PEM_FILE_NAME],
+    )?;
+    let key_file = find_file(&dir, &name, &[KEY_FILE_NAME, KEY_PEM_FILE_NAME])?;
+    let (cert_file, key_file) = match (cert_file, key_file) {
+        (Some(cert_file), Some(key_file)) => (cert_file, key_file),
+        (Some(_), None) => return Err(CertLoadError::MissingKey(name)),
+        (None, Some(_)) => return Err(CertLoadError::MissingCert(name)),
+        (None, None) => return Err(CertLoadError::EmptyDomain(name)),
+    };
+    if is_pem(Path::new(cert_file)) != is_pem(Path::new(key_file)) {
+        return Err(CertLoadError::MixedFormats(name));
     }
-    let cert = CertificateDer::from(
-        std::fs::read(&path).map_err(|_| CertLoadError::MissingCert(domain.clone()))?,
-    );
+
+    load_pair(name, &dir.join(cert_file), &dir.join(key_file))
+}
+
+fn is_pem(file: &Path) -> bool {
+    file.extension().is_some_and(|ext| ext == "pem")
+}
+
+/// Loads a certificate (chain) and its key from the given files. The format of
+/// each file is determined by its extension, `.pem` files are PEM files and
+/// all other files are DER files. The name is used in error messages.
+fn load_pair(
+    name: String,
+    cert_file: &Path,
+    key_file: &Path,
+) -> Result<CertifiedKey, CertLoadError> {
+    // used for error messages
+    let file_name = |file: &Path| {
+        file.file_name().map_or_else(
+            || file.display().to_string(),
+            |f| f.to_string_lossy().into_owned(),
+        )
+    };
+
+    // load certificate from file
+    let data = std::fs::read(cert_file).map_err(|_| CertLoadError::MissingCert(name.clone()))?;
+    let certs = if is_pem(cert_file) {
+        // the end entity certificate comes first, followed by the rest of the chain
+        let certs = CertificateDer::pem_slice_iter(&data)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| {
+                CertLoadError::BadPem(name.clone(), file_name(cert_file), e.to_string())
+            })?;
+        if certs.is_empty() {
+            return Err(CertLoadError::BadPem(
+                name,
+                file_name(cert_file),
+                "no certificates found".to_string(),
+            ));
+        }
+        certs
+    } else {
+        vec![CertificateDer::from(data)]
+    };
 
     // load key from file
-    path.set_file_name(KEY_FILE_NAME);
-    let Ok(der) = std::fs::read(&path) else {
-        return Err(CertLoadError::MissingKey(domain));
+    let Ok(data) = std::fs::read(key_file) else {
+        return Err(CertLoadError::MissingKey(name));
     };
 
     // transform key to correct format
-    let key = der_to_private_key(&der).map_err(|e| CertLoadError::BadKey(domain.clone(), e))?;
+    let key = if is_pem(key_file) {
+        let key = PrivateKeyDer::from_pem_slice(&data)
+            .map_err(|e| CertLoadError::BadPem(name.clone(), file_name(key_file), e.to_string()))?;
+        any_supported_type(&key)
+    } else {
+        der_to_private_key(&data)
+    }
+    .map_err(|e| CertLoadError::BadKey(name.clone(), e))?;
 
-    Ok(CertifiedKey::new(vec![cert], key))
+    let certified_key = CertifiedKey::new(certs, key);
+    match certified_key.keys_match() {
+        // not all key types support this check
+        Ok(()) | Err(rustls::Error::InconsistentKeys(rustls::InconsistentKeys::Unknown)) => {
+            Ok(certified_key)
+        }
+        Err(_) => Err(CertLoadError::KeyMismatch(name)),
+    }
 }
 
 /// We don't know the key type of the private key DER file, so try each
-/// possible type until we find one that works.
+/// possible type until we find one that works. PEM files do not have this
+/// problem because they declare the key type.
 ///
-/// We should probably stop doing this and use a PEM file instead:
 /// <https://github.com/rustls/rustls/issues/1661>
 fn der_to_private_key(der: &[u8]) -> Result<Arc<dyn SigningKey>, rustls::Error> {
     let keys = [
@@ -123,13 +459,22 @@ fn der_to_private_key(der: &[u8]) -> Result<Arc<dyn SigningKey>, rustls::Error>
 
 impl CertStore {
     /// Load certificates from a certificate directory.
-    /// Certificates should be stored in a folder for each hostname, for example
-    /// the certificate and key for `example.com` should be in the files
-    /// `certs_dir/example.com/{cert.der,key.der}` respectively.
+    ///
+    /// If the directory contains a mapping file, only the certificates listed
+    /// in it are loaded, see `load_mapping`.
+    ///
+    /// Otherwise certificates should be stored in a folder for each hostname,
+    /// for example the certificate and key for `example.com` should be in the
+    /// files `certs_dir/example.com/{cert.der,key.der}` respectively.
     ///
     /// If there are `cert.der` and `key.der` directly in `certs_dir`, these
     /// will be loaded as default certificates.
     pub fn load_from(certs_dir: &Path) -> Result<Self, CertLoadError> {
+        let mapping = certs_dir.join(MAPPING_FILE_NAME);
+        if mapping.is_file() {
+            return Self::load_mapping(certs_dir, &mapping);
+        }
+
         // load all certificates from directories
         let mut certs = vec![];
 
@@ -137,20 +482,11 @@ impl CertStore {
         // certificate directory.
         match load_domain(certs_dir, String::new()) {
             Err(CertLoadError::EmptyDomain(_)) => { /* there are no fallback keys */ }
-            Err(CertLoadError::Empty) | Err(CertLoadError::NoReadCertDir) => unreachable!(),
-            Err(CertLoadError::BadKey(_, e)) => {
-                return Err(CertLoadError::BadKey("fallback".to_string(), e));
-            }
-            Err(CertLoadError::MissingKey(_)) => {
-                return Err(CertLoadError::MissingKey("fallback".to_string()));
-            }
-            Err(CertLoadError::MissingCert(_)) => {
-                return Err(CertLoadError::MissingCert("fallback".to_string()));
-            }
+            Err(e) => return Err(e),
             // For the fallback keys there is no domain name to verify them
             // against, so we can skip that step and only have to do it for the
             // other keys below.
-            Ok(key) => certs.push((String::new(), Arc::new(key))),
+            Ok(key) => certs.push((NamePattern::Fallback, Arc::new(key))),
         }
 
         for file in certs_dir
@@ -170,64 +506,384 @@ impl CertStore {
 
             let key = load_domain(certs_dir, filename.clone())?;
 
-            certs.push((filename, Arc::new(key)));
+            let pattern = NamePattern::Subtree(normalize_name(&filename));
+            if !pattern.covered_by(&dns_names(&key.cert[0])) {
+                // this used to be accepted, so only warn about it
+                log::warn!(
+                    "The certificate for {filename} does not include it in its subject alternative names."
+                );
+            }
+            certs.push((pattern, Arc::new(key)));
+        }
+
+        Self::new(certs, vec![])
+    }
+
+    /// Loads the certificates listed in a mapping file. Each section of the
+    /// file is a certificate, for example:
+    /// ```text
+    /// [example.com]
+    /// cert = example.com/fullchain.pem
+    /// key = example.com/key.pem
+    /// names = example.com *.example.com
+    /// ```
+    /// Relative paths are relative to the certificate directory. The names are
+    /// separated by whitespace and can be domain names, wildcard patterns like
+    /// `*.example.com` or `*` for the fallback certificate. Each name has to be
+    /// included in the subject alternative names of the certificate.
+    fn load_mapping(certs_dir: &Path, mapping: &Path) -> Result<Self, CertLoadError> {
+        let mut ini = Ini::new_cs();
+        ini.set_comment_symbols(&['#']);
+        let sections = ini
+            .load(mapping.to_str().expect("mapping file path not UTF-8"))
+            .map_err(CertLoadError::BadMapping)?;
+
+        let mut certs: Vec<(NamePattern, Arc<CertifiedKey>)> = vec![];
+        let mut files = vec![];
+        for (section, mut entries) in sections {
+            if section == "default" {
+                if entries.is_empty() {
+                    continue;
+                }
+                return Err(CertLoadError::BadMapping(
+                    "there are entries outside of a section".to_string(),
+                ));
+            }
+
+            let mut get = |key: &str| {
+                entries.remove(key).flatten().ok_or_else(|| {
+                    CertLoadError::BadMapping(format!("section {section} has no {key} entry"))
+                })
+            };
+            let cert_file = certs_dir.join(get("cert")?);
+            let key_file = certs_dir.join(get("key")?);
+            let names = get("names")?;
+
+            let key = Arc::new(load_pair(section.clone(), &cert_file, &key_file)?);
+            let dns_names = dns_names(&key.cert[0]);
+            for name in names.split_whitespace() {
+                let pattern = NamePattern::parse(name).map_err(CertLoadError::BadMapping)?;
+                if !pattern.covered_by(&dns_names) {
+                    return Err(CertLoadError::NameNotCovered(section, name.to_string()));
+                }
+                if certs.iter().any(|(p, _)| *p == pattern) {
+                    return Err(CertLoadError::BadMapping(format!(
+                        "{name} is mapped to more than one certificate"
+                    )));
+                }
+                certs.push((pattern, key.clone()));
+            }
+
+            files.push(cert_file);
+            files.push(key_file);
         }
 
+        Self::new(certs, files)
+    }
+
+    fn new(
+        certs: Vec<(NamePattern, Arc<CertifiedKey>)>,
+        files: Vec<PathBuf>,
+    ) -> Result<Self, CertLoadError> {
         if certs.is_empty() {
             return Err(CertLoadError::Empty);
         }
 
-        certs.sort_unstable_by(|(a, _), (b, _)| {
-            // Try to match as many domain segments as possible. If one is a
-            // substring of the other, the `zip` will only compare the smaller
-            // length of either a or b and the for loop will not decide.
-            for (a_part, b_part) in a.split('.').rev().zip(b.split('.').rev()) {
-                if a_part != b_part {
-                    // Here we have to make sure that the empty string will
-                    // always be sorted to the end, so we reverse the usual
-                    // ordering of str.
-                    return a_part.cmp(b_part).reverse();
-                }
+        // the same certificate may be used for multiple names
+        let mut infos: Vec<(&Arc<CertifiedKey>, Vec<String>)> = vec![];
+        for (pattern, key) in &certs {
+            match infos.iter_mut().find(|(k, _)| Arc::ptr_eq(k, key)) {
+                Some((_, names)) => names.push(pattern.to_string()),
+                None => infos.push((key, vec![pattern.to_string()])),
             }
-            // Sort longer domains first.
-            a.len().cmp(&b.len()).reverse()
-        });
+        }
+        let infos: Vec<CertInfo> = infos
+            .into_iter()
+            .map(|(key, names)| CertInfo::new(names, &key.cert[0]))
+            .collect();
+        for info in &infos {
+            log::info!("Loaded certificate for {info}");
+        }
+
+        Ok(Self {
+            certs,
+            files,
+            infos,
+        })
+    }
+
+    /// Returns details about each loaded certificate.
+    pub fn infos(&self) -> &[CertInfo] {
+        &self.infos
+    }
+
+    /// Logs a warning for each certificate that is not valid or expires soon.
+    fn warn_about_problems(&self) {
+        for info in &self.infos {
+            if let Some(problem) = info.problem() {
+                log::warn!("The certificate for {} {problem}.", info.names());
+            }
+        }
+    }
 
-        log::debug!(
-            "certs loaded for {:?}",
-            certs.iter().map(|t| &t.0).collect::<Vec<_>>()
-        );
+    /// Checks if a mapping file exists in the certificate directory.
+    pub fn has_mapping(certs_dir: &Path) -> bool {
+        certs_dir.join(MAPPING_FILE_NAME).is_file()
+    }
 
-        Ok(Self { certs })
+    /// Finds the certificate that matches the server name best.
+    fn find(&self, name: &str) -> Option<&Arc<CertifiedKey>> {
+        let name = normalize_name(name);
+        self.certs
+            .iter()
+            .filter_map(|(pattern, key)| Some((pattern.matches(&name)?, key)))
+            .max_by_key(|(specificity, _)| *specificity)
+            .map(|(_, key)| key)
     }
 
     /// Checks if a certificate fitting a specific domain has been loaded.
     /// The same rules about using a certificate at the level above apply.
     pub fn has_domain(&self, domain: &str) -> bool {
-        self.certs.iter().any(|(s, _)| domain.ends_with(s))
+        self.find(domain).is_some()
     }
 }
 
 impl ResolvesServerCert for CertStore {
     fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
         if let Some(name) = client_hello.server_name() {
-            let name: &str = name;
-            // The certificate list is sorted so the longest match will always
-            // appear first. We have to find the first that is either this
-            // domain or a parent domain of the current one.
+            self.find(name).cloned()
+        } else {
+            // Fallback to default cert, if it is present.
             self.certs
                 .iter()
-                .find(|(s, _)| name.ends_with(s))
-                // only the key is interesting
-                .map(|(_, k)| k)
-                .cloned()
-        } else {
-            // Fallback to default cert. Due to the certificate loading logic,
-            // the fallback cert is always the last one, if it is present.
-            match self.certs.last() {
-                Some((domain, key)) if domain.is_empty() => Some(key.clone()),
-                _ => None,
+                .find(|(pattern, _)| *pattern == NamePattern::Fallback)
+                .map(|(_, key)| key.clone())
+        }
+    }
+}
+
+/// How often the certificate directory is checked for changes.
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often the certificates are checked for expiry.
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Resolves certificates using a `CertStore` that can be replaced while the
+/// server is running, so certificates can be changed without a restart.
+#[derive(Debug)]
+pub(crate) struct CertResolver {
+    /// The directory the certificates are loaded from.
+    certs_dir: PathBuf,
+    /// The domains which must have a certificate for a reload to succeed.
+    domains: Vec<String>,
+    store: RwLock<Arc<CertStore>>,
+}
+
+impl CertResolver {
+    pub fn new(certs_dir: PathBuf, domains: Vec<String>, store: CertStore) -> Self {
+        Self {
+            certs_dir,
+            domains,
+            store: RwLock::new(Arc::new(store)),
+        }
+    }
+
+    /// Loads the certificates from the certificate directory again. The new
+    /// certificates are only used if all of them could be loaded and there
+    /// still is a certificate for each configured domain. Otherwise the
+    /// previous certificates stay in use.
+    pub fn reload(&self) -> Result<(), CertLoadError> {
+        let store = CertStore::load_from(&self.certs_dir)?;
+        if let Some(domain) = self.domains.iter().find(|d| !store.has_domain(d)) {
+            return Err(CertLoadError::MissingDomain(domain.clone()));
+        }
+
+        store.warn_about_problems();
+        *self.store.write().expect("certificate store poisoned") = Arc::new(store);
+        Ok(())
+    }
+
+    /// Logs warnings about certificates that are not valid or expire soon,
+    /// immediately and then once a day. This future never completes.
+    pub async fn monitor_expiry(&self) {
+        let mut interval = tokio::time::interval(EXPIRY_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            let store = self
+                .store
+                .read()
+                .expect("certificate store poisoned")
+                .clone();
+            store.warn_about_problems();
+        }
+    }
+
+    /// Reloads the certificates whenever a file in the certificate directory
+    /// changes. This future never completes.
+    pub async fn watch(&self) {
+        let files = || {
+            self.store
+                .read()
+                .expect("certificate store poisoned")
+                .files
+                .clone()
+        };
+        let mut state = files_state(&self.certs_dir, &files());
+        let mut interval = tokio::time::interval(RELOAD_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            let dir = self.certs_dir.clone();
+            let files = files();
+            let new_state = tokio::task::spawn_blocking(move || files_state(&dir, &files))
+                .await
+                .expect("could not check certificate directory");
+            if new_state != state {
+                log::info!("The certificate directory changed, reloading certificates.");
+                state = new_state;
+                self.reload_and_log();
             }
         }
     }
+
+    /// Reloads the certificates and logs the result.
+    pub fn reload_and_log(&self) {
+        match self.reload() {
+            Ok(()) => log::info!("Reloaded certificates."),
+            Err(e) => log::error!("Could not reload certificates, keeping the old ones: {e}"),
+        }
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let store = self
+            .store
+            .read()
+            .expect("certificate store poisoned")
+            .clone();
+        store.resolve(client_hello)
+    }
+}
+
+/// Returns the paths, modification times and sizes of the files in the
+/// certificate directory and the domain directories in it, as well as of the
+/// other given files, to detect changes.
+fn files_state(
+    certs_dir: &Path,
+    other_files: &[PathBuf],
+) -> Vec<(PathBuf, Option<SystemTime>, u64)> {
+    fn entries(dir: &Path) -> impl Iterator<Item = PathBuf> {
+        dir.read_dir()
+            .into_iter()
+            .flatten()
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+    }
+
+    let mut state = vec![];
+    for path in entries(certs_dir).chain(other_files.iter().cloned()) {
+        let files: Vec<PathBuf> = if path.is_dir() {
+            entries(&path).collect()
+        } else {
+            vec![path]
+        };
+        for file in files {
+            // this follows symlinks, since certificates are often symlinked
+            let metadata = file.metadata().ok();
+            let modified = metadata.as_ref().and_then(|m| m.modified().ok());
+            let len = metadata.map_or(0, |m| m.len());
+            state.push((file, modified, len));
+        }
+    }
+    state.sort();
+    state
+}
+
+/// Whether clients are asked for a certificate during the handshake.
+static CLIENT_CERTS_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Makes the server ask clients for a certificate on new connections, because
+/// something can make use of it. Until then, anonymous clients get the same
+/// handshake as without client certificate support.
+pub(crate) fn request_client_certs() {
+    if !CLIENT_CERTS_REQUESTED.swap(true, Ordering::Relaxed) {
+        log::info!("Asking clients for certificates from now on");
+    }
+}
+
+/// Accepts any client certificate, including self signed ones, as is common
+/// for Gemini where client certificates are used in a TOFU manner. Client
+/// certificates are optional and are only asked for after
+/// `request_client_certs` was called. Whether a certificate may access a
+/// resource is checked for each request.
+#[derive(Debug)]
+pub(crate) struct AnyClientCert {
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl AnyClientCert {
+    pub fn new() -> Self {
+        Self {
+            algorithms: default_provider().signature_verification_algorithms,
+        }
+    }
+}
+
+impl ClientCertVerifier for AnyClientCert {
+    fn offer_client_auth(&self) -> bool {
+        CLIENT_CERTS_REQUESTED.load(Ordering::Relaxed)
+    }
+
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        // there are no certificate authorities for client certificates
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        // The validity period is checked when the certificate is used, so the
+        // client can be told what is wrong with the certificate.
+        Ok(ClientCertVerified::assertion())
+    }
+
+    // The handshake signatures still have to be checked to make sure the
+    // client actually has the private key for the certificate.
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// Checks whether the current time is within the validity period of the
+/// certificate. Certificates that can not be parsed are not valid either.
+pub(crate) fn is_currently_valid(cert: &CertificateDer<'_>) -> bool {
+    x509_parser::parse_x509_certificate(cert).is_ok_and(|(_, cert)| cert.validity().is_valid())
 }
@@ -0,0 +1,158 @@
+use {
+    crate::{certificates, codes},
+    std::{net::IpAddr, path::Path, process::Stdio},
+    tokio::{
+        io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt},
+        process::{Child, Command},
+    },
+    tokio_rustls::rustls::ServerConnection,
+    url::Url,
+};
+
+/// The information about a request that is passed on to CGI scripts and
+/// other backends generating dynamic content.
+pub(crate) struct RequestInfo<'a> {
+    /// The complete URL that was requested.
+    pub url: &'a Url,
+    /// The part of the URL path that identifies the script.
+    pub script_name: &'a str,
+    /// The percent-decoded rest of the URL path after the script name.
+    pub path_info: &'a str,
+    /// The IP address of the client, if known.
+    pub remote_addr: Option<IpAddr>,
+    /// The TLS session the request was received on.
+    pub tls: &'a ServerConnection,
+}
+
+impl RequestInfo<'_> {
+    /// Returns the variables describing this request, using the names of the
+    /// CGI environment variables that are commonly used by Gemini servers.
+    pub fn variables(&self) -> Vec<(&'static str, String)> {
+        let mut vars = vec![
+            ("GATEWAY_INTERFACE", "CGI/1.1".to_string()),
+            ("SERVER_PROTOCOL", "GEMINI".to_string()),
+            (
+                "SERVER_SOFTWARE",
+                format!("agate/{}", env!("CARGO_PKG_VERSION")),
+            ),
+            ("GEMINI_URL", self.url.to_string()),
+            ("SCRIPT_NAME", self.script_name.to_string()),
+            ("PATH_INFO", self.path_info.to_string()),
+            (
+                "QUERY_STRING",
+                self.url.query().unwrap_or_default().to_string(),
+            ),
+            (
+                "SERVER_NAME",
+                self.url.host_str().unwrap_or_default().to_string(),
+            ),
+            (
+                "SERVER_PORT",
+                self.url.port().unwrap_or(crate::DEFAULT_PORT).to_string(),
+            ),
+        ];
+
+        if let Some(addr) = self.remote_addr {
+            vars.push(("REMOTE_ADDR", addr.to_string()));
+            vars.push(("REMOTE_HOST", addr.to_string()));
+        }
+
+        if let Some(version) = self.tls.protocol_version().and_then(|v| v.as_str()) {
+            vars.push(("TLS_VERSION", version.to_string()));
+        }
+
+        if let Some(cert) = self.tls.peer_certificates().and_then(|certs| certs.first()) {
+            vars.push(("AUTH_TYPE", "CERTIFICATE".to_string()));
+            vars.push(("TLS_CLIENT_HASH", certificates::fingerprint(cert)));
+        }
+
+        vars
+    }
+}
+
+/// Checks if the file can be executed as a CGI script.
+#[cfg(unix)]
+pub(crate) fn is_executable(metadata: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    metadata.is_file() && metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+pub(crate) fn is_executable(_metadata: &std::fs::Metadata) -> bool {
+    false
+}
+
+/// Starts the CGI script with the given request information in its
+/// environment. The script's working directory is the directory it is located
+/// in. Its standard output is piped so the response can be read from it.
+pub(crate) fn spawn(script: &Path, request: &RequestInfo<'_>) -> std::io::Result<Child> {
+    // relative paths would be resolved from the changed working directory
+    let script = std::path::absolute(script)?;
+    let mut command = Command::new(&script);
+    if let Some(dir) = script.parent() {
+        command.current_dir(dir);
+    }
+    command
+        .env_clear()
+        .envs(std::env::var_os("PATH").map(|path| ("PATH", path)))
+        .envs(request.variables())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        // make sure scripts do not outlive a timed out request
+        .kill_on_drop(true)
+        .spawn()
+}
+
+/// Reads the response header written by a backend and checks that it is a
+/// valid Gemini response header. Both CRLF and a bare LF are accepted as the
+/// line terminator.
+pub(crate) async fn read_header<R>(output: &mut R) -> Result<(u8, String), &'static str>
+where
+    R: AsyncBufRead + Unpin,
+{
+    // two digits, a space, at most 1024 bytes of meta and CRLF
+    let mut line = Vec::with_capacity(1029);
+    (&mut *output)
+        .take(1029)
+        .read_until(b'\n', &mut line)
+        .await
+        .or(Err("could not read response header"))?;
+
+    let Some(line) = line.strip_suffix(b"\n") else {
+        return Err("response header missing or too long");
+    };
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let line = std::str::from_utf8(line).or(Err("response header is not UTF-8"))?;
+
+    let status = line
+        .get(..2)
+        .filter(|status| status.bytes().all(|b| b.is_ascii_digit()))
+        .and_then(|status| status.parse::<u8>().ok())
+        .filter(|&status| codes::is_valid(status))
+        .ok_or("invalid status code in response header")?;
+
+    let meta = match line[2..].strip_prefix(' ') {
+        Some(meta) => meta,
+        None if line.len() == 2 => "",
+        None => return Err("status code is not followed by a space"),
+    };
+    if meta.len() > 1024 {
+        return Err("response meta is too long");
+    }
+
+    Ok((status, meta.to_string()))
+}
+
+/// Converts a relative file path into the corresponding URL path, for example
+/// to be used for `SCRIPT_NAME` or `PATH_INFO`.
+pub(crate) fn url_path(path: &Path) -> String {
+    path.components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .fold(String::new(), |mut url_path, component| {
+            url_path.push('/');
+            url_path.push_str(&component);
+            url_path
+        })
+}
@@ -6,7 +6,28 @@ pub const PROXY_REQUEST_REFUSED: u8 = 53;
 pub const NOT_FOUND: u8 = 51;
 /// The resource requested is no longer available and will not be available again. Search engines and similar tools should remove this resource from their indices. Content aggregators should stop requesting the resource and convey to their human users that the subscribed resource is gone. (cf HTTP 410)
 pub const GONE: u8 = 52;
+/// The server is redirecting the client to a new location for the requested resource. There is no response body. <META> is a new URL for the requested resource. The URL may be absolute or relative. The redirect should be considered temporary, i.e. clients should continue to request the resource at the original address and should not performance convenience actions like automatically updating bookmarks. There is no response body.
+pub const REDIRECT_TEMPORARY: u8 = 30;
 /// The requested resource should be consistently requested from the new URL provided in the future. Tools loke search engine indexers or content aggregators should update their configurations to avoid requesting the old URL, and end-user clients may automatically update bookmarks, etc. Note that clients that only pay attention to the initial digit of status codes will treat this as a temporary redirect. They will still end up at the right place, they just won't be able to make use of the knowledge that this redirect is permanent, so they'll pay a small performance penality by having to follow the redirect each time.
 pub const REDIRECT_PERMANENT: u8 = 31;
 /// The request was handled successfully and a response body will follow the response header. The <META> line is a MIME media type which applies to the response body.
 pub const SUCCESS: u8 = 20;
+/// The request has failed. There is no response body. The nature of the failure is temporary, i.e. an identical request MAY succeed in the future. The contents of <META> may provide additional information on the failure, and should be displayed to human users.
+pub const TEMPORARY_FAILURE: u8 = 40;
+/// A CGI process, or similar system for generating dynamic content, died unexpectedly or timed out.
+pub const CGI_ERROR: u8 = 42;
+/// Rate limiting is in effect. <META> is an integer number of seconds which the client must wait before another request is made to this server.
+pub const SLOW_DOWN: u8 = 44;
+/// The requested resource requires a client certificate to access. If the request was made without a certificate, it should be repeated with one. If the request was made with a certificate, the server did not accept it and the request should be repeated with a different certificate.
+pub const CLIENT_CERTIFICATE_REQUIRED: u8 = 60;
+/// The supplied client certificate is not authorised for accessing the particular requested resource. The problem is not with the certificate itself, which may be authorised for other resources.
+pub const CERTIFICATE_NOT_AUTHORISED: u8 = 61;
+/// The supplied client certificate was not accepted because it is not valid. This indicates a problem with the certificate in and of itself, with no consideration of the particular requested resource. The most likely cause is that the certificate's validity start date is in the future or its expiry date has passed, but this code may also indicate an invalid signature, or a violation of X509 standard requirements.
+pub const CERTIFICATE_NOT_VALID: u8 = 62;
+
+/// Checks that a status code belongs to one of the categories defined by the
+/// Gemini specification, i.e. it is a two digit number starting with a digit
+/// between 1 and 6 inclusive.
+pub fn is_valid(status: u8) -> bool {
+    (10..=69).contains(&status)
+}
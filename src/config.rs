@@ -0,0 +1,228 @@
+use {
+    configparser::ini::Ini,
+    getopts::{Fail, Matches, Options},
+    std::{
+        collections::HashMap,
+        path::{Path, PathBuf},
+        str::FromStr,
+    },
+    url::Host,
+};
+
+/// The section for options that are also available on the command line.
+const MAIN_SECTION: &str = "agate";
+/// The prefix of sections with settings for a single hostname.
+const HOST_SECTION_PREFIX: &str = "host:";
+/// Options that only make sense on the command line.
+const COMMAND_LINE_ONLY: &[&str] = &["config", "help", "version", "check-certs"];
+
+/// Whether directories without an index file are listed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum DirectoryListing {
+    /// Only directories with a `.directory-listing-ok` file are listed.
+    #[default]
+    File,
+    /// All directories are listed, the `.directory-listing-ok` file is only
+    /// used for the preamble.
+    Always,
+    /// No directories are listed.
+    Never,
+}
+
+impl FromStr for DirectoryListing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "file" => Ok(Self::File),
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            _ => Err(format!(
+                "unknown value {s:?}, expected one of file, always or never"
+            )),
+        }
+    }
+}
+
+/// Settings for a single hostname that replace the global settings.
+#[derive(Debug, Default)]
+pub(crate) struct HostConfig {
+    /// The content directory for this hostname.
+    pub content_dir: Option<PathBuf>,
+    /// The default language for text/gemini documents of this hostname.
+    pub language: Option<String>,
+    pub serve_secret: Option<bool>,
+    pub central_config: Option<bool>,
+    pub directory_listing: DirectoryListing,
+    /// Other hostnames with the same content and settings.
+    pub aliases: Vec<String>,
+}
+
+/// The contents of a configuration file.
+#[derive(Debug, Default)]
+pub(crate) struct Config {
+    /// The options from the main section, as command line arguments.
+    pub args: Vec<String>,
+    /// The settings for each hostname, by the name given in the section.
+    pub hosts: Vec<(String, HostConfig)>,
+}
+
+impl Config {
+    /// Reads a configuration file. Keys in the `[agate]` section are the long
+    /// names of command line options, which are checked against `opts`. Flags
+    /// take a boolean value and options that can be given multiple times take
+    /// values separated by commas or on separate lines, other options take
+    /// the whole value. Options that were
+    /// given on the `command_line` are left out, so they replace the ones in
+    /// the file.
+    pub fn load(path: &Path, opts: &Options, command_line: &Matches) -> Result<Self, String> {
+        let mut ini = Ini::new_cs();
+        ini.set_default_section(MAIN_SECTION);
+        ini.set_comment_symbols(&['#', ';']);
+        ini.set_multiline(true);
+        let sections = ini
+            .load(path)
+            .map_err(|e| format!("could not read config file {}: {e}", path.display()))?;
+
+        // point to the offending key in error messages
+        let err = |section: &str, key: &str, msg: String| {
+            format!(
+                "invalid config file {}: key {key:?} in section [{section}]: {msg}",
+                path.display()
+            )
+        };
+
+        let mut config = Self::default();
+        for (section, keys) in sections {
+            if section == MAIN_SECTION {
+                for (key, value) in keys {
+                    let value = value.unwrap_or_default();
+                    let args =
+                        option_args(opts, &key, &value).map_err(|msg| err(&section, &key, msg))?;
+                    if !command_line.opt_present(&key) {
+                        config.args.extend(args);
+                    }
+                }
+            } else if let Some(hostname) = section.strip_prefix(HOST_SECTION_PREFIX) {
+                let mut host = HostConfig::default();
+                for (key, value) in keys {
+                    let value = value.unwrap_or_default();
+                    match key.as_str() {
+                        "content" => host.content_dir = Some(PathBuf::from(value)),
+                        "lang" => host.language = Some(value),
+                        "serve-secret" => {
+                            host.serve_secret =
+                                Some(parse_bool(&value).map_err(|msg| err(&section, &key, msg))?);
+                        }
+                        "central-conf" => {
+                            host.central_config =
+                                Some(parse_bool(&value).map_err(|msg| err(&section, &key, msg))?);
+                        }
+                        "aliases" => {
+                            host.aliases = split_values(&value).map(str::to_string).collect();
+                        }
+                        "directory-listing" => {
+                            host.directory_listing =
+                                value.parse().map_err(|msg| err(&section, &key, msg))?;
+                        }
+                        _ => {
+                            return Err(err(&section, &key, "unknown key".into()));
+                        }
+                    }
+                }
+                config.hosts.push((hostname.to_string(), host));
+            } else {
+                return Err(format!(
+                    "invalid config file {}: unknown section [{section}]",
+                    path.display()
+                ));
+            }
+        }
+        Ok(config)
+    }
+
+    /// Returns the aliases of all hostnames, which are hostnames too.
+    pub fn aliases(&self) -> impl Iterator<Item = &String> {
+        self.hosts.iter().flat_map(|(_, host)| &host.aliases)
+    }
+
+    /// Checks that the host sections are for the given hostnames and returns
+    /// their settings by the normalized hostname.
+    pub fn host_configs(self, hostnames: &[Host]) -> Result<HashMap<String, HostConfig>, String> {
+        self.hosts
+            .into_iter()
+            .map(|(name, host)| {
+                let hostname = Host::parse(&name).map_err(|e| {
+                    format!("invalid hostname in section [{HOST_SECTION_PREFIX}{name}]: {e}")
+                })?;
+                if !hostnames.contains(&hostname) {
+                    return Err(format!(
+                        "the section [{HOST_SECTION_PREFIX}{name}] is not for one of the hostnames"
+                    ));
+                }
+                Ok((hostname.to_string(), host))
+            })
+            .collect()
+    }
+}
+
+/// Converts a key from the configuration file to command line arguments.
+fn option_args(opts: &Options, key: &str, value: &str) -> Result<Vec<String>, String> {
+    if COMMAND_LINE_ONLY.contains(&key) {
+        return Err("this option can only be used on the command line".into());
+    }
+    // getopts would take a single character as a short option
+    if key.chars().count() < 2 || !key.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+        return Err("unknown option, use the long name of the option".into());
+    }
+
+    match opts.parse([format!("--{key}")]) {
+        // the option is a flag
+        Ok(_) => match parse_bool(value)? {
+            true => Ok(vec![format!("--{key}")]),
+            false => Ok(vec![]),
+        },
+        Err(Fail::ArgumentMissing(_))
+            if opts
+                .parse([format!("--{key}=a"), format!("--{key}=b")])
+                .is_err() =>
+        {
+            // the option takes a single value, which may contain commas
+            let value = value.trim();
+            if value.is_empty() {
+                return Err("missing value".into());
+            }
+            Ok(vec![format!("--{key}={value}")])
+        }
+        Err(Fail::ArgumentMissing(_)) => {
+            let args = split_values(value)
+                .map(|value| format!("--{key}={value}"))
+                .collect::<Vec<_>>();
+            if args.is_empty() {
+                return Err("missing value".into());
+            }
+            opts.parse(&args).map_err(|e| e.to_string())?;
+            Ok(args)
+        }
+        Err(Fail::UnrecognizedOption(_)) => Err("unknown option".into()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value.trim() {
+        "true" | "yes" | "on" => Ok(true),
+        "false" | "no" | "off" => Ok(false),
+        _ => Err(format!("expected true or false, found {value:?}")),
+    }
+}
+
+/// Splits a value that may contain several values.
+fn split_values(value: &str) -> impl Iterator<Item = &str> {
+    // lines starting with a bracket would be taken as a section, so IPv6
+    // addresses have to be separated by commas
+    value
+        .split(['\n', ','])
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+}
@@ -1,34 +1,48 @@
 #![forbid(unsafe_code)]
 
+mod accesslog;
+mod cache;
+mod certgen;
 mod certificates;
+mod cgi;
 mod codes;
+mod config;
 mod metadata;
+mod metrics;
+mod proxy;
+mod ratelimit;
+mod scgi;
+#[cfg(unix)]
+mod systemd;
+mod timeout;
+mod titan;
+mod vhosts;
 use codes::*;
-use metadata::{FileOptions, PresetMeta};
+use metadata::{ErrorMessage, FileOptions, PresetMeta};
 
 use {
     percent_encoding::{AsciiSet, CONTROLS, percent_decode_str, percent_encode},
-    rcgen::{CertificateParams, DnType, KeyPair},
     std::{
         borrow::Cow,
+        collections::HashMap,
         error::Error,
         ffi::OsStr,
-        fmt::Write,
-        fs::{self, File},
-        io::Write as _,
         net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
         path::{self, Component, Path, PathBuf},
         sync::{Arc, LazyLock},
+        time::{Duration, SystemTime},
     },
     tokio::{
-        io::{AsyncReadExt, AsyncWriteExt},
+        io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
         net::{TcpListener, TcpStream},
         runtime::Runtime,
-        sync::Mutex,
+        time::{Instant, timeout, timeout_at},
     },
     tokio_rustls::{
         TlsAcceptor,
-        rustls::{server::ServerConfig, version::TLS13},
+        rustls::{
+            ServerConnection, pki_types::CertificateDer, server::ServerConfig, version::TLS13,
+        },
         server::TlsStream,
     },
     url::{Host, Url},
@@ -36,8 +50,11 @@ use {
 
 #[cfg(unix)]
 use {
-    std::os::unix::fs::{FileTypeExt, PermissionsExt},
-    tokio::net::{UnixListener, UnixStream},
+    std::os::unix::fs::FileTypeExt,
+    tokio::{
+        net::{UnixListener, UnixStream},
+        signal::unix::{SignalKind, signal},
+    },
 };
 
 static DEFAULT_PORT: u16 = 1965;
@@ -48,25 +65,59 @@ fn main() {
         env_logger::Env::default().default_filter_or("agate=info"),
     )
     .init();
+    // parse the arguments before other threads are started, because taking
+    // passed sockets modifies the environment
+    LazyLock::force(&ARGS);
     Runtime::new()
         .expect("could not start tokio runtime")
         .block_on(async {
-            let default = PresetMeta::Parameters(
-                ARGS.language
-                    .as_ref()
-                    .map_or(String::new(), |lang| format!(";lang={lang}")),
-            );
-            let mimetypes = Arc::new(Mutex::new(FileOptions::new(default)));
+            let mimetypes = Arc::new(FileOptions::new());
+            // keeps watching until it is dropped
+            let _watcher = if ARGS.watch {
+                mimetypes
+                    .watch()
+                    .inspect_err(|e| {
+                        log::error!(
+                            "Could not watch the content directories, checking .meta files on every request instead: {e}"
+                        );
+                    })
+                    .ok()
+            } else {
+                None
+            };
+            // clients are asked for certificates if something uses them,
+            // CGI and SCGI programs get the certificate of the client
+            if ARGS.cgi || !ARGS.scgi.is_empty() {
+                certificates::request_client_certs();
+            }
 
             // some systems automatically listen in dual stack if the IPv6 unspecified
             // address is used, so don't fail if the second unspecified address gets
             // an error when trying to start
             let mut listening_unspecified = false;
 
-            let mut handles = vec![];
-            for addr in &ARGS.addrs {
-                let arc = mimetypes.clone();
+            // pick up changed certificates without restarting
+            tokio::spawn(ARGS.certs.watch());
+            tokio::spawn(ARGS.certs.monitor_expiry());
+            #[cfg(unix)]
+            tokio::spawn(async {
+                let mut hangup = signal(SignalKind::hangup()).expect("could not listen for SIGHUP");
+                while hangup.recv().await.is_some() {
+                    log::info!("Received SIGHUP, reloading certificates and reopening the access log.");
+                    ARGS.certs.reload_and_log();
+                    ARGS.access_log.reopen();
+                }
+            });
+
+            let shutdown_signal = shutdown_signal();
+            // tells the listeners to stop accepting connections
+            let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+            // every connection holds a sender, so all connections are closed
+            // when the receiver sees that there are no senders left
+            let (drain_tx, mut drain_rx) = tokio::sync::mpsc::channel::<()>(1);
 
+            let mut tcp_listeners = vec![];
+            for addr in &ARGS.addrs {
                 let listener = match TcpListener::bind(addr).await {
                     Err(e) => {
                         if !(addr.ip().is_unspecified() && listening_unspecified) {
@@ -80,34 +131,13 @@ fn main() {
                     Ok(listener) => listener,
                 };
                 listening_unspecified |= addr.ip().is_unspecified();
+                tcp_listeners.push((addr.to_string(), listener));
+            }
 
-                handles.push(tokio::spawn(async move {
-                    log::info!("Started listener on {addr}");
-
-                    loop {
-                        let (stream, _) = listener.accept().await.unwrap_or_else(|e| {
-                            panic!("could not accept new connection on {addr}: {e}")
-                        });
-                        let arc = arc.clone();
-                        tokio::spawn(async {
-                            match RequestHandle::new(stream, arc).await {
-                                Ok(handle) => match handle.handle().await {
-                                    Ok(info) => log::info!("{info}"),
-                                    Err(err) => log::warn!("{err}"),
-                                },
-                                Err(log_line) => {
-                                    log::warn!("{log_line}");
-                                }
-                            }
-                        });
-                    }
-                }))
-            };
-
+            #[cfg_attr(not(unix), allow(unused_mut))]
+            let mut unix_listeners = vec![];
             #[cfg(unix)]
             for socketpath in &ARGS.sockets {
-                let arc = mimetypes.clone();
-
                 if socketpath.exists() && socketpath.metadata()
                         .expect("Failed to get existing socket metadata")
                         .file_type()
@@ -122,23 +152,113 @@ fn main() {
                     }
                     Ok(listener) => listener,
                 };
+                unix_listeners.push((socketpath.display().to_string(), listener));
+            }
+
+            #[cfg(unix)]
+            for inherited in std::mem::take(&mut *ARGS.inherited.lock().unwrap()) {
+                let name = &inherited.name;
+                match inherited.fd_name {
+                    Some(fd_name) => log::info!("Using passed socket {fd_name} for {name}"),
+                    None => log::info!("Using passed socket for {name}"),
+                }
+                match inherited.socket {
+                    systemd::Socket::Tcp(listener) => {
+                        let listener = listener
+                            .set_nonblocking(true)
+                            .and_then(|()| TcpListener::from_std(listener))
+                            .unwrap_or_else(|e| panic!("Failed to listen on {name}: {e}"));
+                        tcp_listeners.push((inherited.name, listener));
+                    }
+                    systemd::Socket::Unix(listener) => {
+                        let listener = listener
+                            .set_nonblocking(true)
+                            .and_then(|()| UnixListener::from_std(listener))
+                            .unwrap_or_else(|e| panic!("Failed to listen on {name}: {e}"));
+                        unix_listeners.push((inherited.name, listener));
+                    }
+                }
+            }
+
+            let mut handles = vec![];
+            for (addr, listener) in tcp_listeners {
+                let arc = mimetypes.clone();
+                let mut shutdown = shutdown_rx.clone();
+                let drain = drain_tx.clone();
+                let limits = ARGS.limits[&addr].clone();
+                let proxy_protocol = ARGS.proxy_protocol.contains(&addr);
+
+                handles.push(tokio::spawn(async move {
+                    log::info!("Started listener on {addr}");
+
+                    loop {
+                        let (permits, stream) = tokio::select! {
+                            _ = shutdown.changed() => break,
+                            accepted = async {
+                                let permits = limits.acquire().await;
+                                (permits, listener.accept().await)
+                            } => accepted,
+                        };
+                        let (stream, _) = stream.unwrap_or_else(|e| {
+                            panic!("could not accept new connection on {addr}: {e}")
+                        });
+                        let open = metrics::METRICS.connection_accepted();
+                        let arc = arc.clone();
+                        let rate_limit = limits.rate.clone();
+                        let drain = drain.clone();
+                        tokio::spawn(async move {
+                            // keep the connection counted until it is closed
+                            let _permits = permits;
+                            let _drain = drain;
+                            let _open = open;
+                            match RequestHandle::new(stream, arc, rate_limit, proxy_protocol).await {
+                                Ok(handle) => handle.handle().await,
+                                Err(entry) => {
+                                    metrics::METRICS.connection_failed(&entry);
+                                    ARGS.access_log.write(&entry);
+                                }
+                            }
+                        });
+                    }
+                }))
+            };
+
+            #[cfg(unix)]
+            for (path, listener) in unix_listeners {
+                let arc = mimetypes.clone();
+                let mut shutdown = shutdown_rx.clone();
+                let drain = drain_tx.clone();
+                let limits = ARGS.limits[&path].clone();
+                let proxy_protocol = ARGS.proxy_protocol.contains(&path);
 
                 handles.push(tokio::spawn(async move {
-                    log::info!("Started listener on {}", socketpath.display());
+                    log::info!("Started listener on {path}");
 
                     loop {
-                        let (stream, _) = listener.accept().await.unwrap_or_else(|e| {
-                            panic!("could not accept new connection on {}: {}", socketpath.display(), e)
+                        let (permits, stream) = tokio::select! {
+                            _ = shutdown.changed() => break,
+                            accepted = async {
+                                let permits = limits.acquire().await;
+                                (permits, listener.accept().await)
+                            } => accepted,
+                        };
+                        let (stream, _) = stream.unwrap_or_else(|e| {
+                            panic!("could not accept new connection on {path}: {e}")
                         });
+                        let open = metrics::METRICS.connection_accepted();
                         let arc = arc.clone();
-                        tokio::spawn(async {
-                            match RequestHandle::new_unix(stream, arc).await {
-                                Ok(handle) => match handle.handle().await {
-                                    Ok(info) => log::info!("{info}"),
-                                    Err(err) => log::warn!("{err}"),
-                                },
-                                Err(log_line) => {
-                                    log::warn!("{log_line}");
+                        let rate_limit = limits.rate.clone();
+                        let drain = drain.clone();
+                        tokio::spawn(async move {
+                            // keep the connection counted until it is closed
+                            let _permits = permits;
+                            let _drain = drain;
+                            let _open = open;
+                            match RequestHandle::new_unix(stream, arc, rate_limit, proxy_protocol).await {
+                                Ok(handle) => handle.handle().await,
+                                Err(entry) => {
+                                    metrics::METRICS.connection_failed(&entry);
+                                    ARGS.access_log.write(&entry);
                                 }
                             }
                         });
@@ -146,10 +266,82 @@ fn main() {
                 }))
             };
 
+            if let Some(address) = &ARGS.metrics {
+                let listener = metrics::Listener::bind(address)
+                    .await
+                    .unwrap_or_else(|e| panic!("Failed to listen on {address}: {e}"));
+                log::info!("Started metrics listener on {address}");
+                tokio::spawn(listener.run());
+            }
+
+            drop(drain_tx);
+            #[cfg(unix)]
+            {
+                systemd::notify_ready();
+                tokio::spawn(systemd::watchdog());
+            }
+
+            let signal = shutdown_signal.await;
+            let start = Instant::now();
+            log::info!("Received {signal}, shutting down.");
+            #[cfg(unix)]
+            systemd::notify_stopping();
+
+            // stop accepting connections
+            let _ = shutdown_tx.send(true);
             futures_util::future::join_all(handles).await;
+            #[cfg(unix)]
+            for socketpath in &ARGS.sockets {
+                if let Err(e) = std::fs::remove_file(socketpath) {
+                    log::warn!("Could not remove socket {}: {e}", socketpath.display());
+                }
+            }
+            if let Some(metrics::Address::Unix(path)) = &ARGS.metrics
+                && let Err(e) = std::fs::remove_file(path)
+            {
+                log::warn!("Could not remove socket {}: {e}", path.display());
+            }
+
+            let open = drain_rx.sender_strong_count();
+            if open > 0 {
+                log::info!("Waiting for {open} open connections to finish.");
+            }
+            match timeout(ARGS.grace_period, drain_rx.recv()).await {
+                Ok(_) => log::info!(
+                    "All connections finished, shut down in {:.1} seconds.",
+                    start.elapsed().as_secs_f64()
+                ),
+                Err(_) => log::warn!(
+                    "Closing {} connections that did not finish within {} seconds.",
+                    drain_rx.sender_strong_count(),
+                    ARGS.grace_period.as_secs()
+                ),
+            }
         });
 }
 
+/// Returns a future that resolves to the name of the signal when agate is
+/// asked to stop. The signal handlers are registered immediately, so signals
+/// are not missed before the future is awaited.
+fn shutdown_signal() -> impl Future<Output = &'static str> {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal(SignalKind::terminate()).expect("could not listen for SIGTERM");
+        let mut interrupt = signal(SignalKind::interrupt()).expect("could not listen for SIGINT");
+        async move {
+            tokio::select! {
+                _ = terminate.recv() => "SIGTERM",
+                _ = interrupt.recv() => "SIGINT",
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    async {
+        let _ = tokio::signal::ctrl_c().await;
+        "Ctrl-C"
+    }
+}
+
 type Result<T = (), E = Box<dyn Error + Send + Sync>> = std::result::Result<T, E>;
 
 static ARGS: LazyLock<Args> = LazyLock::new(|| {
@@ -163,20 +355,67 @@ struct Args {
     addrs: Vec<SocketAddr>,
     #[cfg(unix)]
     sockets: Vec<PathBuf>,
-    content_dir: PathBuf,
-    certs: Arc<certificates::CertStore>,
-    hostnames: Vec<Host>,
-    language: Option<String>,
-    serve_secret: bool,
+    /// Listening sockets passed in by the service manager, which are taken
+    /// when the listeners are started.
+    #[cfg(unix)]
+    inherited: std::sync::Mutex<Vec<systemd::Listener>>,
+    certs: Arc<certificates::CertResolver>,
+    vhosts: vhosts::VirtualHosts,
     log_ips: bool,
     only_tls13: bool,
-    central_config: bool,
     skip_port_check: bool,
+    cgi: bool,
+    cgi_timeout: Duration,
+    handshake_timeout: Duration,
+    request_timeout: Duration,
+    write_timeout: Duration,
+    grace_period: Duration,
+    titan_max_size: u64,
+    scgi: Vec<scgi::Route>,
+    /// The limits for each listener, by the name used in the options.
+    limits: HashMap<String, ratelimit::Limits>,
+    /// The listeners whose connections start with a PROXY protocol header.
+    proxy_protocol: Vec<String>,
+    access_log: accesslog::AccessLog,
+    /// Where to serve metrics over HTTP, if at all.
+    metrics: Option<metrics::Address>,
+    cache: Option<cache::Cache>,
+    watch: bool,
+}
+
+/// Prints details about the certificates and any problems with them. Returns
+/// whether there were no problems.
+fn check_certs(certs_path: &Path, hostnames: &[String]) -> Result<bool> {
+    let certs = certificates::CertStore::load_from(certs_path)?;
+
+    let mut ok = true;
+    for info in certs.infos() {
+        println!("{info}");
+        if let Some(problem) = info.problem() {
+            println!("  problem: the certificate {problem}");
+            ok = false;
+        }
+    }
+    for hostname in hostnames {
+        if let Host::Domain(domain) = Host::parse(hostname)?
+            && !certs.has_domain(&domain)
+        {
+            println!("problem: there is no certificate for {domain}");
+            ok = false;
+        }
+    }
+    Ok(ok)
 }
 
 fn args() -> Result<Args> {
     let args: Vec<String> = std::env::args().collect();
     let mut opts = getopts::Options::new();
+    opts.optopt(
+        "",
+        "config",
+        "Read options from a configuration file, options on the command line replace the ones in the file",
+        "FILE",
+    );
     opts.optopt(
         "",
         "content",
@@ -216,6 +455,11 @@ fn args() -> Result<Args> {
     );
     opts.optflag("h", "help", "Print this help text and exit.");
     opts.optflag("V", "version", "Print version information and exit.");
+    opts.optflag(
+        "",
+        "check-certs",
+        "Print details about the certificates and exit. Exits with an error if a certificate is not valid, expires within 14 days or there is no certificate for a hostname.",
+    );
     opts.optflag(
         "3",
         "only-tls13",
@@ -227,21 +471,161 @@ fn args() -> Result<Args> {
         "Enable serving secret files (files/directories starting with a dot)",
     );
     opts.optflag("", "log-ip", "Output the remote IP address when logging.");
+    opts.optopt(
+        "",
+        "access-log",
+        "Write the access log to FILE instead of the normal log, - means standard output. The file is reopened on SIGHUP.",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "metrics",
+        "Serve metrics in the Prometheus text format over plain HTTP at /metrics on ADDR, which has to be a loopback address, or on a Unix socket if it is a path",
+        "ADDR",
+    );
+    opts.optflag(
+        "",
+        "metrics-public",
+        "Allow serving metrics on addresses other than loopback addresses, which makes them reachable from other hosts without authentication.",
+    );
+    opts.optopt(
+        "",
+        "access-log-format",
+        "Format of the access log: agate (default), json or common",
+        "FORMAT",
+    );
     opts.optflag(
         "C",
         "central-conf",
         "Use a central .meta file in the content root directory. Decentral config files will be ignored.",
     );
+    opts.optflag(
+        "",
+        "watch",
+        "Watch the content directories for changes (e.g. with inotify) instead of checking .meta files on every request.",
+    );
     opts.optflag(
         "e",
         "ed25519",
         "Generate keys using the Ed25519 signature algorithm instead of the default ECDSA.",
     );
+    opts.optopt(
+        "",
+        "cert-algorithm",
+        "Key algorithm for generated certificates: ecdsa (default), ecdsa-p384, ed25519, rsa (2048 bits), rsa3072 or rsa4096",
+        "ALG",
+    );
+    opts.optopt(
+        "",
+        "cert-validity",
+        "Number of days generated certificates are valid (default until the year 4096)",
+        "DAYS",
+    );
+    opts.optmulti(
+        "",
+        "cert-san",
+        "Additional domain name or IP address to include in generated certificates, only for the certificate of HOSTNAME if given (multiple occurences means multiple names)",
+        "[HOSTNAME=]NAME",
+    );
+    opts.optopt(
+        "",
+        "cert-subject",
+        "Subject fields for generated certificates, e.g. \"O=Example,C=DE\" (allowed fields are CN, O, OU, L, ST and C; the CN defaults to the hostname)",
+        "FIELDS",
+    );
+    opts.optflag(
+        "",
+        "regenerate-expired",
+        "Replace expired self signed certificates for the hostnames at startup, keeping their keys.",
+    );
     opts.optflag(
         "",
         "skip-port-check",
         "Skip URL port check even when a hostname is specified.",
     );
+    opts.optflag(
+        "",
+        "cgi",
+        "Run executable files in the content directory as CGI scripts.",
+    );
+    opts.optopt(
+        "",
+        "cgi-timeout",
+        "Number of seconds a CGI script may run before it is stopped (default 10)",
+        "SECS",
+    );
+    opts.optopt(
+        "",
+        "handshake-timeout",
+        "Number of seconds a client may take to complete the TLS handshake (default 10)",
+        "SECS",
+    );
+    opts.optopt(
+        "",
+        "request-timeout",
+        "Number of seconds a client may take to send the request after the TLS handshake (default 10)",
+        "SECS",
+    );
+    opts.optopt(
+        "",
+        "write-timeout",
+        "Number of seconds a client may stop reading the response before the connection is closed (default 30)",
+        "SECS",
+    );
+    opts.optopt(
+        "",
+        "grace-period",
+        "Number of seconds to wait for open connections to finish when shutting down (default 30)",
+        "SECS",
+    );
+    opts.optopt(
+        "",
+        "titan-max-size",
+        "Maximum size of files uploaded with the Titan protocol (default 10 MiB)",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "cache-size",
+        "Keep up to BYTES of small files and directory listings in memory, which are checked for changes on every request (default no cache)",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "cache-entries",
+        "Maximum number of files and directory listings in the cache (default 1024)",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "cache-file-size",
+        "Maximum size of a single file in the cache (default 64 KiB)",
+        "BYTES",
+    );
+    opts.optmulti(
+        "",
+        "rate-limit",
+        "Allow each client (by IP address, or /64 prefix for IPv6) at most REQUESTS requests in SECONDS seconds, only on LISTENER if given (an --addr or --socket value; multiple occurences means different limits per listener)",
+        "[LISTENER=]REQUESTS/SECONDS",
+    );
+    opts.optmulti(
+        "",
+        "max-connections",
+        "Maximum number of concurrent connections, only to LISTENER if given, otherwise for all listeners together (multiple occurences means different limits per listener)",
+        "[LISTENER=]NUMBER",
+    );
+    opts.optmulti(
+        "",
+        "proxy-protocol",
+        "Expect connections to LISTENER (an --addr or --socket value) to start with a PROXY protocol header and take the client address from it, for use behind a load balancer (multiple occurences means multiple listeners)",
+        "LISTENER",
+    );
+    opts.optmulti(
+        "",
+        "scgi",
+        "Forward requests for paths starting with PREFIX to an SCGI backend, which is either HOST:PORT or unix:PATH, only for HOSTNAME if given (multiple occurences means multiple backends)",
+        "[HOSTNAME]PREFIX=BACKEND",
+    );
 
     let matches = opts.parse(&args[1..]).map_err(|f| f.to_string())?;
 
@@ -255,8 +639,31 @@ fn args() -> Result<Args> {
         std::process::exit(0);
     }
 
+    let (matches, config) = match matches.opt_str("config") {
+        Some(path) => {
+            let config = config::Config::load(Path::new(&path), &opts, &matches)?;
+            let matches = opts
+                .parse(config.args.iter().chain(&args[1..]))
+                .map_err(|f| f.to_string())?;
+            (matches, config)
+        }
+        None => (matches, config::Config::default()),
+    };
+
     // try to open the certificate directory
     let certs_path = matches.opt_get_default("certs", ".certificates".to_string())?;
+
+    // aliases from the configuration file need certificates too
+    let hostname_args: Vec<String> = matches
+        .opt_strs("hostname")
+        .into_iter()
+        .chain(config.aliases().cloned())
+        .collect();
+
+    if matches.opt_present("check-certs") {
+        let ok = check_certs(Path::new(&certs_path), &hostname_args)?;
+        std::process::exit(if ok { 0 } else { 1 });
+    }
     let (certs, certs_path) = match check_path(certs_path.clone()) {
         // the directory exists, try to load certificates
         Ok(certs_path) => match certificates::CertStore::load_from(&certs_path) {
@@ -264,7 +671,7 @@ fn args() -> Result<Args> {
             Ok(certs) => (Some(certs), certs_path),
             // the certificate directory did not contain certificates, but we can generate some
             // because the hostname option was given
-            Err(certificates::CertLoadError::Empty) if matches.opt_present("hostname") => {
+            Err(certificates::CertLoadError::Empty) if !hostname_args.is_empty() => {
                 (None, certs_path)
             }
             // failed loading certificates or missing hostname to generate them
@@ -284,58 +691,61 @@ fn args() -> Result<Args> {
     // This ensures we get the right error message.
     let mut reload_certs = certs.is_none();
 
+    let algorithm = match matches.opt_get("cert-algorithm")? {
+        Some(_) if matches.opt_present("e") => {
+            return Err(
+                "The options --ed25519 and --cert-algorithm can not be used together.".into(),
+            );
+        }
+        Some(algorithm) => algorithm,
+        None if matches.opt_present("e") => certgen::KeyAlgorithm::Ed25519,
+        None => certgen::KeyAlgorithm::EcdsaP256,
+    };
+    let generator = certgen::Generator {
+        algorithm,
+        validity: matches
+            .opt_get::<u64>("cert-validity")?
+            .map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+        extra_names: matches
+            .opt_strs("cert-san")
+            .into_iter()
+            .map(|san| match san.split_once('=') {
+                Some((hostname, name)) => (Some(hostname.to_string()), name.to_string()),
+                None => (None, san),
+            })
+            .collect(),
+        subject: matches
+            .opt_str("cert-subject")
+            .map(|s| certgen::parse_subject(&s))
+            .transpose()?
+            .unwrap_or_default(),
+    };
+    let regenerate_expired = matches.opt_present("regenerate-expired");
+
     let mut hostnames = vec![];
-    for s in matches.opt_strs("hostname") {
+    for s in hostname_args {
         // normalize hostname, add punycoding if necessary
         let hostname = Host::parse(&s)?;
 
+        if let Host::Domain(ref domain) = hostname
+            && regenerate_expired
+            && generator.regenerate_if_expired(&certs_path, domain)?
+        {
+            reload_certs = true;
+        }
+
         // check if we have a certificate for that domain
         if let Host::Domain(ref domain) = hostname
             && !matches!(certs, Some(ref certs) if certs.has_domain(domain))
         {
-            log::info!("No certificate or key found for {s:?}, generating them.");
-
-            let mut cert_params = CertificateParams::new(vec![domain.clone()])?;
-            cert_params
-                .distinguished_name
-                .push(DnType::CommonName, domain);
-
-            // <CertificateParams as Default>::default() already implements a
-            // date in the far future from the time of writing: 4096-01-01
-
-            let key_pair = if matches.opt_present("e") {
-                KeyPair::generate_for(&rcgen::PKCS_ED25519)
-            } else {
-                KeyPair::generate()
-            }?;
-
-            // generate the certificate with the configuration
-            let cert = cert_params.self_signed(&key_pair)?;
-
-            // make sure the certificate directory exists
-            let cert_dir = certs_path.join(domain);
-            fs::create_dir(&cert_dir)?;
-
-            // write certificate data to disk
-            let mut cert_file = File::create(cert_dir.join(certificates::CERT_FILE_NAME))?;
-            cert_file.write_all(cert.der())?;
-
-            // write key data to disk
-            let key_file_path = cert_dir.join(certificates::KEY_FILE_NAME);
-            let mut key_file = File::create(&key_file_path)?;
-            #[cfg(unix)]
-            {
-                // set permissions so only owner can read
-                match key_file.set_permissions(std::fs::Permissions::from_mode(0o400)) {
-                    Ok(_) => (),
-                    Err(_) => log::warn!(
-                        "could not set permissions for new key file {}",
-                        key_file_path.display()
-                    ),
-                }
+            // generated certificates would not be used because only the
+            // certificates in the mapping file are loaded
+            if certificates::CertStore::has_mapping(&certs_path) {
+                return Err(certificates::CertLoadError::MissingDomain(domain.clone()).into());
             }
-            key_file.write_all(key_pair.serialized_der())?;
 
+            log::info!("No certificate or key found for {s:?}, generating them.");
+            generator.generate(&certs_path, domain)?;
             reload_certs = true;
         }
 
@@ -349,6 +759,34 @@ fn args() -> Result<Args> {
         // there must already have been certificates loaded
         certs.unwrap()
     };
+    let domains = hostnames
+        .iter()
+        .filter_map(|host| match host {
+            Host::Domain(domain) => Some(domain.clone()),
+            _ => None,
+        })
+        .collect();
+    let certs = certificates::CertResolver::new(certs_path, domains, certs);
+
+    let mut hosts = config.host_configs(&hostnames)?;
+    for host in hosts.values_mut() {
+        if let Some(content_dir) = host.content_dir.take() {
+            host.content_dir = Some(check_path(content_dir.display().to_string())?);
+        }
+    }
+    let root = check_path(matches.opt_get_default("content", "content".into())?)?;
+    let vhosts = vhosts::VirtualHosts::new(
+        &hostnames,
+        hosts,
+        vhosts::VirtualHost {
+            config_root: root.clone(),
+            root,
+            language: matches.opt_str("lang"),
+            serve_secret: matches.opt_present("serve-secret"),
+            central_config: matches.opt_present("central-conf"),
+            directory_listing: config::DirectoryListing::default(),
+        },
+    )?;
 
     // parse listening addresses
     let mut addrs = vec![];
@@ -360,16 +798,26 @@ fn args() -> Result<Args> {
     let mut empty = addrs.is_empty();
 
     #[cfg(unix)]
-    let mut sockets = vec![];
+    let mut sockets: Vec<PathBuf> = vec![];
+    #[cfg(unix)]
+    let inherited = systemd::listen_fds()?;
     #[cfg(unix)]
     {
         for i in matches.opt_strs("socket") {
             sockets.push(i.parse()?);
         }
 
-        empty &= sockets.is_empty();
+        empty &= sockets.is_empty() && inherited.is_empty();
     }
 
+    let mut scgi = vec![];
+    for i in matches.opt_strs("scgi") {
+        scgi.push(i.parse::<scgi::Route>()?);
+    }
+    // check longer prefixes first so they can take precedence over shorter
+    // ones, and routes for a single host before the ones for all hosts
+    scgi.sort_by_key(|route| (std::cmp::Reverse(route.prefix.len()), route.host.is_none()));
+
     if empty {
         addrs = vec![
             SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), DEFAULT_PORT),
@@ -377,22 +825,140 @@ fn args() -> Result<Args> {
         ];
     }
 
+    #[cfg_attr(not(unix), allow(unused_mut))]
+    let mut listeners: Vec<String> = addrs.iter().map(SocketAddr::to_string).collect();
+    #[cfg(unix)]
+    listeners.extend(sockets.iter().map(|path| path.display().to_string()));
+    #[cfg(unix)]
+    listeners.extend(inherited.iter().map(|listener| listener.name.clone()));
+    let limits = listener_limits(
+        &listeners,
+        ratelimit::parse_per_listener(matches.opt_strs("rate-limit"))?,
+        ratelimit::parse_per_listener(matches.opt_strs("max-connections"))?,
+    )?;
+    let proxy_protocol = matches
+        .opt_strs("proxy-protocol")
+        .iter()
+        .map(|listener| {
+            let listener = ratelimit::normalize_listener(listener);
+            if listeners.contains(&listener) {
+                Ok(listener)
+            } else {
+                Err(format!(
+                    "There is no listener {listener:?} to use the PROXY protocol on."
+                ))
+            }
+        })
+        .collect::<Result<_, _>>()?;
+
     Ok(Args {
         addrs,
         #[cfg(unix)]
         sockets,
-        content_dir: check_path(matches.opt_get_default("content", "content".into())?)?,
+        #[cfg(unix)]
+        inherited: std::sync::Mutex::new(inherited),
         certs: Arc::new(certs),
-        hostnames,
-        language: matches.opt_str("lang"),
-        serve_secret: matches.opt_present("serve-secret"),
+        vhosts,
         log_ips: matches.opt_present("log-ip"),
         only_tls13: matches.opt_present("only-tls13"),
-        central_config: matches.opt_present("central-conf"),
         skip_port_check: matches.opt_present("skip-port-check"),
+        cgi: matches.opt_present("cgi"),
+        cgi_timeout: Duration::from_secs(matches.opt_get_default("cgi-timeout", 10)?),
+        handshake_timeout: Duration::from_secs(matches.opt_get_default("handshake-timeout", 10)?),
+        request_timeout: Duration::from_secs(matches.opt_get_default("request-timeout", 10)?),
+        write_timeout: Duration::from_secs(matches.opt_get_default("write-timeout", 30)?),
+        grace_period: Duration::from_secs(matches.opt_get_default("grace-period", 30)?),
+        titan_max_size: matches.opt_get_default("titan-max-size", 10 * 1024 * 1024)?,
+        scgi,
+        limits,
+        proxy_protocol,
+        access_log: accesslog::AccessLog::new(
+            matches.opt_get_default("access-log-format", accesslog::Format::default())?,
+            matches.opt_str("access-log").map(PathBuf::from),
+        )?,
+        metrics: metrics_address(&matches)?,
+        watch: matches.opt_present("watch"),
+        cache: match matches.opt_get("cache-size")? {
+            Some(size) => Some(cache::Cache::new(
+                size,
+                matches.opt_get_default("cache-entries", 1024)?,
+                matches.opt_get_default("cache-file-size", 64 * 1024)?,
+            )),
+            None => None,
+        },
     })
 }
 
+/// Returns the address for the metrics listener, which has to be a loopback
+/// address unless `--metrics-public` is used.
+fn metrics_address(matches: &getopts::Matches) -> Result<Option<metrics::Address>> {
+    let address = matches.opt_get("metrics")?;
+    if let Some(metrics::Address::Tcp(addr)) = &address
+        && !addr.ip().is_loopback()
+    {
+        if !matches.opt_present("metrics-public") {
+            return Err(format!(
+                "The metrics address {addr} is not a loopback address, use --metrics-public to serve metrics on it anyway."
+            )
+            .into());
+        }
+        log::warn!("Serving metrics on {addr} without authentication, they may be public.");
+    }
+    Ok(address)
+}
+
+/// Sets up the limits for each listener from the `--rate-limit` and
+/// `--max-connections` options. Limits without a listener apply to all
+/// listeners that do not have their own limit, except that the number of
+/// connections is limited for all listeners together.
+fn listener_limits(
+    listeners: &[String],
+    rate_limits: Vec<(Option<String>, ratelimit::RateLimit)>,
+    max_connections: Vec<(Option<String>, usize)>,
+) -> Result<HashMap<String, ratelimit::Limits>> {
+    let unknown = rate_limits
+        .iter()
+        .map(|(listener, _)| listener)
+        .chain(max_connections.iter().map(|(listener, _)| listener))
+        .flatten()
+        .find(|listener| !listeners.contains(listener));
+    if let Some(listener) = unknown {
+        return Err(format!("There is no listener {listener:?} to apply limits to.").into());
+    }
+
+    // the last occurrence of an option wins
+    let find = |listener: Option<&str>| {
+        (
+            rate_limits
+                .iter()
+                .rev()
+                .find(|(l, _)| l.as_deref() == listener)
+                .map(|(_, limit)| Arc::new(ratelimit::RateLimiter::new(*limit))),
+            max_connections
+                .iter()
+                .rev()
+                .find(|(l, _)| l.as_deref() == listener)
+                .map(|(_, max)| Arc::new(tokio::sync::Semaphore::new(*max))),
+        )
+    };
+    // the default rate limiter is shared, so requests to different listeners
+    // count towards the same limit
+    let (default_rate, global_connections) = find(None);
+
+    Ok(listeners
+        .iter()
+        .map(|listener| {
+            let (rate, connections) = find(Some(listener));
+            let limits = ratelimit::Limits {
+                rate: rate.or_else(|| default_rate.clone()),
+                connections,
+                global_connections: global_connections.clone(),
+            };
+            (listener.clone(), limits)
+        })
+        .collect())
+}
+
 fn check_path(s: String) -> Result<PathBuf, String> {
     let p = PathBuf::from(s);
     if p.as_path().exists() {
@@ -402,6 +968,88 @@ fn check_path(s: String) -> Result<PathBuf, String> {
     }
 }
 
+/// Maps the path of the URL to a path in the content directory. Returns the
+/// root directory for the host and the path, or `None` if the URL path could
+/// lead outside of the content directory.
+fn content_path(url: &Url) -> Result<Option<(PathBuf, PathBuf)>> {
+    // existence of host_str was checked by parse_request already
+    let root = ARGS.vhosts.root(url.host_str().expect("no hostname"));
+    let mut path = root.clone();
+
+    if let Some(segments) = url.path_segments() {
+        // append percent-decoded path segments
+        for segment in segments {
+            // To prevent directory traversal attacks, we need to
+            // check that each filesystem path component in the URL
+            // path segment is a normal component (not the root
+            // directory, the parent directory, a drive label, or
+            // another special component). Furthermore, since path
+            // separators (e.g. the escaped forward slash %2F) in a
+            // single URL path segment are non-structural, the URL
+            // path segment should not contain multiple filesystem
+            // path components.
+            let decoded = percent_decode_str(segment).decode_utf8()?;
+            let mut components = Path::new(decoded.as_ref()).components();
+            // the first component must be a normal component; if
+            // so, push it onto the PathBuf
+            match components.next() {
+                None => (),
+                Some(Component::Normal(c)) => path.push(c),
+                Some(_) => return Ok(None),
+            }
+            // there must not be more than one component
+            if components.next().is_some() {
+                return Ok(None);
+            }
+            // even if it's one component, there may be trailing path
+            // separators at the end
+            if decoded.ends_with(path::is_separator) {
+                return Ok(None);
+            }
+        }
+    }
+
+    Ok(Some((root, path)))
+}
+
+/// Lists the files in a directory as sorted gemtext links, skipping hidden
+/// files.
+async fn read_listing(path: &Path) -> Result<String> {
+    // https://url.spec.whatwg.org/#path-percent-encode-set
+    const ENCODE_SET: AsciiSet = CONTROLS
+        .add(b' ')
+        .add(b'"')
+        .add(b'#')
+        .add(b'<')
+        .add(b'>')
+        .add(b'?')
+        .add(b'`')
+        .add(b'{')
+        .add(b'}');
+
+    let mut entries = tokio::fs::read_dir(path).await?;
+    let mut lines = vec![];
+    while let Some(entry) = entries.next_entry().await? {
+        let mut name = entry
+            .file_name()
+            .into_string()
+            .or(Err("Non-Unicode filename"))?;
+        if name.starts_with('.') {
+            continue;
+        }
+        if entry.file_type().await?.is_dir() {
+            name += "/";
+        }
+        let line = match percent_encode(name.as_bytes(), &ENCODE_SET).into() {
+            Cow::Owned(url) => format!("=> {url} {name}\n"),
+            Cow::Borrowed(url) => format!("=> {url}\n"), // url and name are identical
+        };
+        lines.push(line);
+    }
+    lines.sort();
+    Ok(lines.concat())
+}
+
 /// TLS configuration.
 static TLS: LazyLock<TlsAcceptor> = LazyLock::new(acceptor);
 
@@ -411,59 +1059,60 @@ fn acceptor() -> TlsAcceptor {
     } else {
         ServerConfig::builder()
     }
-    .with_no_client_auth()
+    .with_client_cert_verifier(Arc::new(certificates::AnyClientCert::new()))
     .with_cert_resolver(ARGS.certs.clone());
     TlsAcceptor::from(Arc::new(config))
 }
 
 struct RequestHandle<T> {
-    stream: TlsStream<T>,
-    local_port_check: Option<u16>,
-    log_line: String,
-    metadata: Arc<Mutex<FileOptions>>,
+    stream: accesslog::CountWrites<TlsStream<timeout::WriteTimeout<T>>>,
+    /// The address the client connected to, if known.
+    local_addr: Option<SocketAddr>,
+    remote_addr: Option<IpAddr>,
+    entry: accesslog::Entry,
+    metadata: metadata::RequestOptions,
+    /// The rate limit for the listener the request was received on.
+    rate_limit: Option<Arc<ratelimit::RateLimiter>>,
+    /// Data the client sent after the request line, i.e. the start of a Titan
+    /// upload.
+    received: Vec<u8>,
 }
 
 impl RequestHandle<TcpStream> {
     /// Creates a new request handle for the given stream. If establishing the TLS
     /// session fails, returns a corresponding log line.
-    async fn new(stream: TcpStream, metadata: Arc<Mutex<FileOptions>>) -> Result<Self, String> {
-        let local_addr = stream.local_addr().unwrap().to_string();
+    async fn new(
+        stream: TcpStream,
+        metadata: Arc<FileOptions>,
+        rate_limit: Option<Arc<ratelimit::RateLimiter>>,
+        proxy_protocol: bool,
+    ) -> Result<Self, accesslog::Entry> {
+        let local_addr = stream.local_addr().unwrap();
 
         // try to get the remote IP address if desired
-        let peer_addr = if ARGS.log_ips {
-            stream
-                .peer_addr()
-                .map_err(|_| {
-                    format!(
-                        // use nonexistent status code 01 if peer IP is unknown
-                        "{local_addr} - \"\" 01 \"IP error\" error:could not get peer address",
-                    )
-                })?
-                .ip()
-                .to_string()
-        } else {
-            // Do not log IP address, but something else so columns still line up.
-            "-".into()
-        };
-
-        let log_line = format!("{local_addr} {peer_addr}",);
-
-        let local_port_check = if ARGS.skip_port_check {
-            None
-        } else {
-            Some(stream.local_addr().unwrap().port())
+        let remote_addr = match stream.peer_addr() {
+            Ok(addr) => Some(addr),
+            Err(_) if ARGS.log_ips && !proxy_protocol => {
+                // use nonexistent status code 01 if peer IP is unknown
+                return Err(accesslog::Entry::new(local_addr.to_string(), None).failed(
+                    1,
+                    "IP error",
+                    "could not get peer address",
+                ));
+            }
+            Err(_) => None,
         };
 
-        match TLS.accept(stream).await {
-            Ok(stream) => Ok(Self {
-                stream,
-                local_port_check,
-                log_line,
-                metadata,
-            }),
-            // use nonexistent status code 00 if connection was not established
-            Err(e) => Err(format!("{log_line} \"\" 00 \"TLS error\" error:{e}")),
-        }
+        Self::accept(
+            stream,
+            local_addr.to_string(),
+            Some(local_addr),
+            remote_addr,
+            proxy_protocol,
+            metadata,
+            rate_limit,
+        )
+        .await
     }
 }
 
@@ -471,10 +1120,12 @@ impl RequestHandle<TcpStream> {
 impl RequestHandle<UnixStream> {
     async fn new_unix(
         stream: UnixStream,
-        metadata: Arc<Mutex<FileOptions>>,
-    ) -> Result<Self, String> {
-        let log_line = format!(
-            "unix:{} -",
+        metadata: Arc<FileOptions>,
+        rate_limit: Option<Arc<ratelimit::RateLimiter>>,
+        proxy_protocol: bool,
+    ) -> Result<Self, accesslog::Entry> {
+        let listener = format!(
+            "unix:{}",
             stream
                 .local_addr()
                 .ok()
@@ -482,79 +1133,114 @@ impl RequestHandle<UnixStream> {
                 .unwrap_or_default()
         );
 
-        match TLS.accept(stream).await {
-            Ok(stream) => Ok(Self {
-                stream,
-                // TODO add port check for unix sockets, requires extra arg for port
-                local_port_check: None,
-                log_line,
-                metadata,
-            }),
-            // use nonexistent status code 00 if connection was not established
-            Err(e) => Err(format!("{log_line} \"\" 00 \"TLS error\" error:{e}")),
-        }
-    }
-}
-
-trait CheckHost {
-    fn check_host(&self, host: &url::Host) -> bool;
-}
-
-fn check_domain(domain: &str) -> bool {
-    if ARGS.hostnames.is_empty() {
-        // no hostnames -> hostname check disabled
-        true
-    } else {
-        ARGS.hostnames.iter().any(|x| x == &Host::Domain(domain))
+        // the addresses are only known if a proxy sends them
+        Self::accept(
+            stream,
+            listener,
+            None,
+            None,
+            proxy_protocol,
+            metadata,
+            rate_limit,
+        )
+        .await
     }
 }
 
-impl CheckHost for TcpStream {
-    fn check_host(&self, host: &url::Host) -> bool {
-        match host {
-            url::Host::Ipv4(ip) => self
-                .local_addr()
-                .is_ok_and(|local| &local.ip().to_canonical() == ip),
-            url::Host::Ipv6(ip) => self.local_addr().is_ok_and(|local| match local.ip() {
-                IpAddr::V4(local) => &local.to_ipv6_mapped() == ip,
-                IpAddr::V6(local) => &local == ip,
-            }),
-            url::Host::Domain(domain) => check_domain(domain),
+impl<T> RequestHandle<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Reads the PROXY protocol header if the listener expects one and
+    /// establishes the TLS session. `listener` is used as the local address
+    /// in the access log.
+    async fn accept(
+        mut stream: T,
+        listener: String,
+        mut local_addr: Option<SocketAddr>,
+        mut remote_addr: Option<SocketAddr>,
+        proxy_protocol: bool,
+        metadata: Arc<FileOptions>,
+        rate_limit: Option<Arc<ratelimit::RateLimiter>>,
+    ) -> Result<Self, accesslog::Entry> {
+        let deadline = Instant::now() + ARGS.handshake_timeout;
+
+        if proxy_protocol {
+            let entry = || accesslog::Entry::new(listener.clone(), None);
+            match timeout_at(deadline, proxy::read_header(&mut stream)).await {
+                Ok(Ok(Some(addrs))) => {
+                    local_addr = Some(addrs.destination);
+                    remote_addr = Some(addrs.source);
+                }
+                // the proxy does not know the client, e.g. for health checks
+                Ok(Ok(None)) => (),
+                // use nonexistent status code 01 if peer IP is unknown
+                Ok(Err(e)) => return Err(entry().failed(1, "IP error", e)),
+                // use nonexistent status code 02 if the client was too slow
+                Err(_) => {
+                    return Err(entry().failed(2, "Timeout", "PROXY protocol header timed out"));
+                }
+            }
         }
-    }
-}
 
-#[cfg(unix)]
-impl CheckHost for UnixStream {
-    fn check_host(&self, host: &url::Host) -> bool {
-        match host {
-            url::Host::Ipv4(..) | url::Host::Ipv6(..) => true,
-            url::Host::Domain(domain) => check_domain(domain),
+        let remote_addr = remote_addr.map(|addr| addr.ip());
+        let entry = accesslog::Entry::new(listener, remote_addr.filter(|_| ARGS.log_ips));
+
+        let stream = timeout::WriteTimeout::new(stream, ARGS.write_timeout);
+        match timeout_at(deadline, TLS.accept(stream)).await {
+            Ok(Ok(stream)) => Ok(Self {
+                stream: accesslog::CountWrites::new(stream),
+                local_addr,
+                remote_addr,
+                entry,
+                metadata: metadata::RequestOptions::new(metadata),
+                rate_limit,
+                received: Vec::new(),
+            }),
+            // use nonexistent status code 00 if connection was not established
+            Ok(Err(e)) => Err(entry.failed(0, "TLS error", e)),
+            // use nonexistent status code 02 if the client was too slow
+            Err(_) => Err(entry.failed(2, "Timeout", "TLS handshake timed out")),
         }
     }
 }
 
 impl<T> RequestHandle<T>
 where
-    T: AsyncWriteExt + AsyncReadExt + Unpin + CheckHost,
+    T: AsyncWriteExt + AsyncReadExt + Unpin,
 {
-    /// Do the necessary actions to handle this request. Returns a corresponding
-    /// log line as Err or Ok, depending on if the request finished with or
-    /// without errors.
-    async fn handle(mut self) -> Result<String, String> {
+    /// Do the necessary actions to handle this request and write the result to
+    /// the access log.
+    async fn handle(mut self) {
+        self.entry.sni = self.tls().server_name().map(str::to_string);
+
         // not already in error condition
-        let result = match self.parse_request().await {
-            Ok(url) => self.send_response(url).await,
-            Err((status, msg)) => self.send_header(status, msg).await,
+        let result = match timeout(ARGS.request_timeout, self.parse_request()).await {
+            Ok(Ok(url)) => match self.check_rate_limit() {
+                Ok(()) if url.scheme() == "titan" => self.receive_upload(url).await,
+                Ok(()) => self.send_response(url).await,
+                Err(seconds) => self.send_header(SLOW_DOWN, &seconds.to_string()).await,
+            },
+            Ok(Err((status, msg))) => self.send_header(status, msg).await,
+            // use nonexistent status code 02 if the client was too slow
+            Err(_) => {
+                self.entry.status = Some(2);
+                self.entry.meta = Some("Timeout".into());
+                Err("request was not received in time".into())
+            }
         };
 
         let close_result = self.stream.shutdown().await;
 
-        match (result, close_result) {
-            (Err(e), _) => Err(format!("{} error:{}", self.log_line, e)),
-            (Ok(_), Err(e)) => Err(format!("{} error:{}", self.log_line, e)),
-            (Ok(_), Ok(_)) => Ok(self.log_line),
+        self.entry.cert = self.client_cert().map(certificates::fingerprint);
+        self.entry.bytes_sent = self.stream.written();
+        if let Err(e) = result {
+            self.entry.error = Some(e.to_string());
+        } else if let Err(e) = close_result {
+            self.entry.error = Some(e.to_string());
         }
+        metrics::METRICS.request_handled(&self.entry);
+        ARGS.access_log.write(&self.entry);
     }
 
     /// Return the URL requested by the client.
@@ -576,32 +1262,27 @@ where
                 break Err((BAD_REQUEST, "Request ended unexpectedly"));
             };
             len += bytes_read;
-            if request[..len].ends_with(b"\r\n") {
-                break Ok(());
+            // the body of a Titan upload may follow the request line directly
+            if let Some(end) = request[..len].windows(2).position(|w| w == b"\r\n") {
+                break Ok(end);
             } else if bytes_read == 0 {
                 break Err((BAD_REQUEST, "Request ended unexpectedly"));
             }
             buf = &mut request[len..];
         }
-        .and_then(|()| {
-            std::str::from_utf8(&request[..len - 2]).or(Err((BAD_REQUEST, "Non-UTF-8 request")))
+        .and_then(|end| {
+            self.received = request[end + 2..len].to_vec();
+            std::str::from_utf8(&request[..end]).or(Err((BAD_REQUEST, "Non-UTF-8 request")))
         });
 
-        let request = result.inspect_err(|_| {
-            // write empty request to log line for uniformity
-            write!(self.log_line, " \"\"").unwrap();
-        })?;
-
-        // log literal request (might be different from or not an actual URL)
-        self.log_line.push_str(" \"");
-        self.log_line.extend(request.escape_default());
-        self.log_line.push('"');
+        let request = result?;
+        self.entry.request = Some(titan::redact_token(request));
 
         let mut url = Url::parse(request).or(Err((BAD_REQUEST, "Invalid URL")))?;
 
         // Validate the URL:
         // correct scheme
-        if url.scheme() != "gemini" {
+        if !matches!(url.scheme(), "gemini" | "titan") {
             return Err((PROXY_REQUEST_REFUSED, "Unsupported URL scheme"));
         }
 
@@ -637,14 +1318,15 @@ where
             }
         };
         // check for correct host
-        if !self.stream.get_ref().0.check_host(&host) {
+        if !self.check_host(&host) {
             return Err((PROXY_REQUEST_REFUSED, "Proxy request refused"));
         }
 
         // Validate that the port in the URL is the same as for the stream this request
         // came in on.
-        if let Some(expected_port) = self.local_port_check
-            && url.port().unwrap_or(DEFAULT_PORT) != expected_port
+        if let Some(local_addr) = self.local_addr
+            && !ARGS.skip_port_check
+            && url.port().unwrap_or(DEFAULT_PORT) != local_addr.port()
         {
             return Err((PROXY_REQUEST_REFUSED, "Proxy request refused"));
         }
@@ -654,58 +1336,74 @@ where
 
     /// Send the client the file located at the requested URL.
     async fn send_response(&mut self, url: Url) -> Result {
-        let mut path = std::path::PathBuf::from(&ARGS.content_dir);
+        let host = url.host_str().expect("no hostname");
+        let scgi_route = ARGS
+            .scgi
+            .iter()
+            .find_map(|route| Some((route, route.strip_prefix(host, url.path())?)));
+
+        let Some((root, mut path)) = content_path(&url)? else {
+            return self.send_header(NOT_FOUND, "Not found, sorry.").await;
+        };
 
-        if ARGS.hostnames.len() > 1 {
-            // basic vhosts, existence of host_str was checked by parse_request already
-            path.push(url.host_str().expect("no hostname"));
+        if let Some((route, path_info)) = scgi_route {
+            // the paths of a backend can be protected like files
+            if !self.check_client_cert(&path).await? {
+                return Ok(());
+            }
+            let path_info = percent_decode_str(path_info).decode_utf8()?.into_owned();
+            return self.proxy_scgi(&url, route, &path_info).await;
         }
 
-        if let Some(mut segments) = url.path_segments() {
-            // append percent-decoded path segments
-            for segment in segments.clone() {
-                // To prevent directory traversal attacks, we need to
-                // check that each filesystem path component in the URL
-                // path segment is a normal component (not the root
-                // directory, the parent directory, a drive label, or
-                // another special component). Furthermore, since path
-                // separators (e.g. the escaped forward slash %2F) in a
-                // single URL path segment are non-structural, the URL
-                // path segment should not contain multiple filesystem
-                // path components.
-                let decoded = percent_decode_str(segment).decode_utf8()?;
-                let mut components = Path::new(decoded.as_ref()).components();
-                // the first component must be a normal component; if
-                // so, push it onto the PathBuf
-                match components.next() {
-                    None => (),
-                    Some(Component::Normal(c)) => path.push(c),
-                    Some(_) => return self.send_header(NOT_FOUND, "Not found, sorry.").await,
+        if let Some((status, target)) = self.metadata.redirect(&path).await {
+            // the target is an absolute path on this host or a URL
+            return match url.join(&target) {
+                Ok(target) => self.send_header(status, target.as_str()).await,
+                Err(e) => {
+                    self.send_header(NOT_FOUND, "Not found, sorry.").await?;
+                    Err(format!("invalid redirect target {target:?}: {e}").into())
                 }
-                // there must not be more than one component
-                if components.next().is_some() {
-                    return self.send_header(NOT_FOUND, "Not found, sorry.").await;
-                }
-                // even if it's one component, there may be trailing path
-                // separators at the end
-                if decoded.ends_with(path::is_separator) {
-                    return self.send_header(NOT_FOUND, "Not found, sorry.").await;
-                }
-            }
+            };
+        }
+
+        if let Some(mut segments) = url.path_segments() {
             // check if hiding files is disabled
-            if !ARGS.serve_secret
+            if !ARGS.vhosts.get(url.host_str().expect("no hostname")).serve_secret
                 // there is a configuration for this file, assume it should be served
-                && !self.metadata.lock().await.exists(&path)
+                && !self.metadata.exists(&path).await
                 // check if file or directory is hidden
                 && segments.any(|segment| segment.starts_with('.'))
             {
                 return self
-                    .send_header(GONE, "If I told you, it would not be a secret.")
+                    .send_error(
+                        GONE,
+                        &path,
+                        ErrorMessage::Secret,
+                        "If I told you, it would not be a secret.",
+                    )
                     .await;
             }
         }
 
-        if let Ok(metadata) = tokio::fs::metadata(&path).await
+        let metadata = tokio::fs::metadata(&path).await;
+
+        if metadata.is_err() {
+            // The path might lead into a CGI script, in which case the rest of
+            // the path is passed on to the script.
+            for script in path.ancestors().skip(1) {
+                if script == root || !script.starts_with(&root) {
+                    break;
+                }
+                if self.is_cgi(script).await {
+                    if !self.check_client_cert(script).await? {
+                        return Ok(());
+                    }
+                    return self.run_cgi(&url, &root, script, &path).await;
+                }
+            }
+        }
+
+        if let Ok(metadata) = metadata
             && metadata.is_dir()
         {
             if url.path().ends_with('/') || url.path().is_empty() {
@@ -715,8 +1413,12 @@ where
                 path.push("index.gmi");
                 if !path.exists() {
                     path.pop();
+                    if !self.check_client_cert(&path).await? {
+                        return Ok(());
+                    }
                     // try listing directory
-                    return self.list_directory(&path).await;
+                    let vhost = ARGS.vhosts.get(url.host_str().expect("no hostname"));
+                    return self.list_directory(&path, vhost.directory_listing).await;
                 }
             } else {
                 // if client is not redirected, links may not work as expected without trailing slash
@@ -726,63 +1428,453 @@ where
             }
         }
 
-        let data = self.metadata.lock().await.get(&path);
+        if !self.check_client_cert(&path).await? {
+            return Ok(());
+        }
 
-        if let PresetMeta::FullHeader(status, meta) = data {
-            self.send_header(status, &meta).await?;
-            // do not try to access the file
+        // A cached file only has to be checked for changes, its header is
+        // still valid if no sidecar file changed since it was cached.
+        let meta_version = self.metadata.version();
+        let mut cached = None;
+        if let Some(cache) = &ARGS.cache
+            && let Ok(metadata) = tokio::fs::metadata(&path).await
+            && !(ARGS.cgi && cgi::is_executable(&metadata))
+        {
+            cached = cache.get(cache::Kind::File, &path, &metadata, meta_version);
+        }
+        if let Some(cached) = cached {
+            self.send_header(SUCCESS, &cached.header).await?;
+            self.stream.write_all(&cached.data).await?;
             return Ok(());
         }
 
+        let data = self.metadata.get(&path).await;
+
+        match data {
+            PresetMeta::FullHeader(status, meta) => {
+                match ErrorMessage::for_status(status) {
+                    Some(kind) if meta.is_empty() => {
+                        self.send_error(status, &path, kind, "").await?;
+                    }
+                    _ => self.send_header(status, &meta).await?,
+                }
+                // do not try to access the file
+                return Ok(());
+            }
+            PresetMeta::Cgi => return self.run_cgi(&url, &root, &path, &path).await,
+            _ => (),
+        }
+
+        let read_at = SystemTime::now();
         // Make sure the file opens successfully before sending a success header.
         let mut file = match tokio::fs::File::open(&path).await {
             Ok(file) => file,
             Err(e) => {
-                self.send_header(NOT_FOUND, "Not found, sorry.").await?;
+                self.send_error(
+                    NOT_FOUND,
+                    &path,
+                    ErrorMessage::NotFound,
+                    "Not found, sorry.",
+                )
+                .await?;
                 return Err(e.into());
             }
         };
+        let metadata = file.metadata().await;
+
+        if ARGS.cgi && metadata.as_ref().is_ok_and(cgi::is_executable) {
+            return self.run_cgi(&url, &root, &path, &path).await;
+        }
 
         // Send header.
         let mime = match data {
-            // this was already handled before opening the file
-            PresetMeta::FullHeader(..) => unreachable!(),
+            // these were already handled before opening the file
+            PresetMeta::FullHeader(..) | PresetMeta::Cgi => unreachable!(),
             // treat this as the full MIME type
             PresetMeta::FullMime(mime) => mime.clone(),
-            // guess the MIME type and add the parameters
+            // use the guessed MIME type and add the parameters
             PresetMeta::Parameters(params) => {
                 if path.extension() == Some(OsStr::new("gmi")) {
                     format!("text/gemini{params}")
                 } else {
-                    let mime = mime_guess::from_path(&path).first_or_octet_stream();
-                    format!("{}{}", mime.essence_str(), params)
+                    let mime = mime_guess::from_path(&path)
+                        .first_raw()
+                        .unwrap_or("application/octet-stream");
+                    format!("{mime}{params}")
                 }
             }
         };
-        self.send_header(SUCCESS, &mime).await?;
 
         // Send body.
-        tokio::io::copy(&mut file, &mut self.stream).await?;
+        match (&ARGS.cache, metadata) {
+            (Some(cache), Ok(metadata))
+                if metadata.is_file() && metadata.len() <= cache.max_file_size =>
+            {
+                let mut data = Vec::with_capacity(metadata.len() as usize);
+                file.read_to_end(&mut data).await?;
+                let cached = cache::Cached {
+                    data: data.into(),
+                    header: mime.into(),
+                };
+                cache.insert(
+                    cache::Kind::File,
+                    &path,
+                    &metadata,
+                    read_at,
+                    meta_version,
+                    cached.clone(),
+                );
+                self.send_header(SUCCESS, &cached.header).await?;
+                self.stream.write_all(&cached.data).await?;
+            }
+            _ => {
+                self.send_header(SUCCESS, &mime).await?;
+                tokio::io::copy(&mut file, &mut self.stream).await?;
+            }
+        }
         Ok(())
     }
 
-    async fn list_directory(&mut self, path: &Path) -> Result {
-        // https://url.spec.whatwg.org/#path-percent-encode-set
-        const ENCODE_SET: AsciiSet = CONTROLS
-            .add(b' ')
-            .add(b'"')
-            .add(b'#')
-            .add(b'<')
-            .add(b'>')
-            .add(b'?')
-            .add(b'`')
-            .add(b'{')
-            .add(b'}');
+    /// Receive a file uploaded with the Titan protocol and store it at the
+    /// requested path, if uploads are allowed there.
+    async fn receive_upload(&mut self, mut url: Url) -> Result {
+        let upload = match titan::Upload::from_url(&mut url) {
+            Ok(upload) => upload,
+            Err(msg) => return self.send_header(BAD_REQUEST, msg).await,
+        };
+        if upload.size > ARGS.titan_max_size {
+            return self
+                .send_header(BAD_REQUEST, "The upload is too large.")
+                .await;
+        }
 
-        // check if directory listing is enabled by getting preamble
-        let Ok(preamble) = std::fs::read_to_string(path.join(".directory-listing-ok")) else {
-            self.send_header(NOT_FOUND, "Directory index disabled.")
+        let path = match content_path(&url)? {
+            Some((root, path)) if path != root => path,
+            _ => {
+                return self
+                    .send_header(BAD_REQUEST, "Uploading is not allowed here.")
+                    .await;
+            }
+        };
+        // hidden files can not be uploaded unless they can be served, and
+        // sidecar files can never be uploaded so permissions can not be
+        // changed by uploading; neither can CGI scripts, which would run
+        // whatever was uploaded
+        let hidden = url
+            .path_segments()
+            .is_some_and(|mut segments| segments.any(|segment| segment.starts_with('.')));
+        let serve_secret = ARGS
+            .vhosts
+            .get(url.host_str().expect("no hostname"))
+            .serve_secret;
+        let permission = if (hidden && !serve_secret)
+            || path.file_name() == Some(OsStr::new(metadata::SIDECAR_FILENAME))
+            || ARGS.cgi
+            || matches!(self.metadata.get(&path).await, PresetMeta::Cgi)
+        {
+            None
+        } else {
+            self.metadata.upload_permission(&path).await
+        };
+        let Some(permission) = permission else {
+            return self
+                .send_error(
+                    BAD_REQUEST,
+                    &path,
+                    ErrorMessage::BadRequest,
+                    "Uploading is not allowed here.",
+                )
+                .await;
+        };
+
+        // a valid token is enough, otherwise the client certificate is checked
+        let denied = if permission.allows_token(upload.token.as_deref()) {
+            None
+        } else {
+            match (permission.cert_requirement(), self.client_cert()) {
+                (None, _) => Some((CERTIFICATE_NOT_AUTHORISED, "Invalid token.")),
+                (Some(_), None) => {
+                    Some((CLIENT_CERTIFICATE_REQUIRED, "Client certificate required."))
+                }
+                (Some(_), Some(cert)) if !certificates::is_currently_valid(cert) => {
+                    Some((CERTIFICATE_NOT_VALID, "Client certificate is not valid."))
+                }
+                (Some(requirement), Some(cert))
+                    if !requirement.allows(&certificates::fingerprint(cert)) =>
+                {
+                    Some((
+                        CERTIFICATE_NOT_AUTHORISED,
+                        "Client certificate is not authorised.",
+                    ))
+                }
+                (Some(_), Some(_)) => None,
+            }
+        };
+        if let Some((status, meta)) = denied {
+            return self.send_header(status, meta).await;
+        }
+
+        if tokio::fs::metadata(&path)
+            .await
+            .is_ok_and(|metadata| metadata.is_dir())
+        {
+            return self
+                .send_header(BAD_REQUEST, "Can not replace a directory.")
+                .await;
+        }
+
+        let result = if upload.size == 0 {
+            // an empty upload deletes the file
+            match tokio::fs::remove_file(&path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            }
+        } else {
+            // the path is inside the content directory, so are its parents
+            if let Err(e) =
+                tokio::fs::create_dir_all(path.parent().expect("no parent directory")).await
+            {
+                log::info!("could not create the directory for {path:?}: {e}");
+                return self
+                    .send_header(BAD_REQUEST, "Can not create the directory.")
+                    .await;
+            }
+            let received = std::mem::take(&mut self.received);
+            let body = received.as_slice().chain(&mut self.stream);
+            titan::store(&path, body, upload.size, ARGS.request_timeout).await
+        };
+        if let Err(e) = result {
+            self.send_header(TEMPORARY_FAILURE, "Could not store the upload.")
                 .await?;
+            return Err(e.into());
+        }
+
+        // redirect to the uploaded file
+        url.set_scheme("gemini").expect("could not change scheme");
+        self.send_header(REDIRECT_TEMPORARY, url.as_str()).await
+    }
+
+    /// Checks that the host of a request is one this server is for. IP
+    /// addresses have to be the address the client connected to, if known.
+    fn check_host(&self, host: &url::Host) -> bool {
+        match host {
+            url::Host::Ipv4(ip) => self
+                .local_addr
+                .is_none_or(|local| &local.ip().to_canonical() == ip),
+            url::Host::Ipv6(ip) => self.local_addr.is_none_or(|local| match local.ip() {
+                IpAddr::V4(local) => &local.to_ipv6_mapped() == ip,
+                IpAddr::V6(local) => &local == ip,
+            }),
+            url::Host::Domain(domain) => ARGS.vhosts.accepts(domain),
+        }
+    }
+
+    /// Checks that the client did not send too many requests. Otherwise returns
+    /// the number of seconds it has to wait.
+    fn check_rate_limit(&self) -> Result<(), u64> {
+        match (&self.rate_limit, self.remote_addr) {
+            (Some(limiter), Some(addr)) => limiter.check(addr),
+            _ => Ok(()),
+        }
+    }
+
+    fn tls(&self) -> &ServerConnection {
+        self.stream.get_ref().get_ref().1
+    }
+
+    /// Returns the certificate the client sent, if any.
+    fn client_cert(&self) -> Option<&CertificateDer<'static>> {
+        self.tls()
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+    }
+
+    /// Checks that the client certificate meets the requirements configured
+    /// for the file or directory at the specified path. If it does not, an
+    /// error response is sent and false is returned.
+    async fn check_client_cert(&mut self, path: &Path) -> Result<bool> {
+        let Some(requirement) = self.metadata.cert_requirement(path).await else {
+            // no requirements
+            return Ok(true);
+        };
+
+        let (status, meta) = match self.client_cert() {
+            None => (CLIENT_CERTIFICATE_REQUIRED, "Client certificate required."),
+            Some(cert) if !certificates::is_currently_valid(cert) => {
+                (CERTIFICATE_NOT_VALID, "Client certificate is not valid.")
+            }
+            Some(cert) if !requirement.allows(&certificates::fingerprint(cert)) => (
+                CERTIFICATE_NOT_AUTHORISED,
+                "Client certificate is not authorised.",
+            ),
+            Some(_) => return Ok(true),
+        };
+
+        self.send_header(status, meta).await?;
+        Ok(false)
+    }
+
+    /// Checks if the file at the specified path should be run as a CGI script.
+    async fn is_cgi(&self, path: &Path) -> bool {
+        match tokio::fs::metadata(path).await {
+            Ok(metadata) if metadata.is_file() => {
+                (ARGS.cgi && cgi::is_executable(&metadata))
+                    || matches!(self.metadata.get(path).await, PresetMeta::Cgi)
+            }
+            _ => false,
+        }
+    }
+
+    /// Run a CGI script and send its output to the client. `path` is the
+    /// requested path, which is either the script itself or leads into it.
+    async fn run_cgi(&mut self, url: &Url, root: &Path, script: &Path, path: &Path) -> Result {
+        let script_name = cgi::url_path(script.strip_prefix(root).unwrap_or(script));
+        let path_info = cgi::url_path(path.strip_prefix(script).unwrap_or(Path::new("")));
+        let request = cgi::RequestInfo {
+            url,
+            script_name: &script_name,
+            path_info: &path_info,
+            remote_addr: self.remote_addr,
+            tls: self.tls(),
+        };
+
+        let mut child = match cgi::spawn(script, &request) {
+            Ok(child) => child,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                self.send_header(NOT_FOUND, "Not found, sorry.").await?;
+                return Err(e.into());
+            }
+            Err(e) => {
+                self.send_header(CGI_ERROR, "Could not start CGI script.")
+                    .await?;
+                return Err(e.into());
+            }
+        };
+
+        // the timeout applies to the whole run of the script
+        let deadline = Instant::now() + ARGS.cgi_timeout;
+        let mut output = BufReader::new(child.stdout.take().expect("stdout is not piped"));
+
+        let (status, meta) = match timeout_at(deadline, cgi::read_header(&mut output)).await {
+            Ok(Ok(header)) => header,
+            Ok(Err(e)) => {
+                self.send_header(CGI_ERROR, "CGI script sent an invalid response.")
+                    .await?;
+                return Err(e.into());
+            }
+            Err(_) => {
+                self.send_header(CGI_ERROR, "CGI script timed out.").await?;
+                return Err("CGI script timed out".into());
+            }
+        };
+        self.send_header(status, &meta).await?;
+
+        // Send body.
+        timeout_at(deadline, tokio::io::copy(&mut output, &mut self.stream))
+            .await
+            .or(Err("CGI script timed out"))??;
+
+        let exit_status = timeout_at(deadline, child.wait())
+            .await
+            .or(Err("CGI script timed out"))??;
+        if !exit_status.success() {
+            return Err(format!("CGI script failed with {exit_status}").into());
+        }
+        Ok(())
+    }
+
+    /// Forward the request to an SCGI backend and relay the backend's response
+    /// to the client.
+    async fn proxy_scgi(&mut self, url: &Url, route: &scgi::Route, path_info: &str) -> Result {
+        let vars = cgi::RequestInfo {
+            url,
+            script_name: &route.prefix,
+            path_info,
+            remote_addr: self.remote_addr,
+            tls: self.tls(),
+        }
+        .variables();
+
+        // the timeout only applies until the response header is received,
+        // since backends may be streaming the body
+        let deadline = Instant::now() + ARGS.cgi_timeout;
+        let result = match &route.backend {
+            scgi::Backend::Tcp(addr) => {
+                match timeout_at(deadline, TcpStream::connect(addr)).await {
+                    Ok(Ok(backend)) => return self.relay_scgi(backend, &vars, deadline).await,
+                    Ok(Err(e)) => Err(e.into()),
+                    Err(e) => Err(e.into()),
+                }
+            }
+            #[cfg(unix)]
+            scgi::Backend::Unix(path) => {
+                match timeout_at(deadline, UnixStream::connect(path)).await {
+                    Ok(Ok(backend)) => return self.relay_scgi(backend, &vars, deadline).await,
+                    Ok(Err(e)) => Err(e.into()),
+                    Err(e) => Err(e.into()),
+                }
+            }
+        };
+
+        self.send_header(CGI_ERROR, "Could not connect to backend.")
+            .await?;
+        result
+    }
+
+    /// Send the request to a connected SCGI backend and relay its response.
+    async fn relay_scgi<S>(
+        &mut self,
+        backend: S,
+        vars: &[(&str, String)],
+        deadline: Instant,
+    ) -> Result
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        let mut response = BufReader::new(backend);
+        let header = timeout_at(deadline, async {
+            scgi::send_request(response.get_mut(), vars)
+                .await
+                .or(Err("could not send request to backend"))?;
+            cgi::read_header(&mut response).await
+        })
+        .await;
+
+        let (status, meta) = match header {
+            Ok(Ok(header)) => header,
+            Ok(Err(e)) => {
+                self.send_header(CGI_ERROR, "Backend sent an invalid response.")
+                    .await?;
+                return Err(e.into());
+            }
+            Err(_) => {
+                self.send_header(CGI_ERROR, "Backend timed out.").await?;
+                return Err("SCGI backend timed out".into());
+            }
+        };
+        self.send_header(status, &meta).await?;
+
+        // Send body.
+        tokio::io::copy(&mut response, &mut self.stream).await?;
+        Ok(())
+    }
+
+    async fn list_directory(&mut self, path: &Path, listing: config::DirectoryListing) -> Result {
+        // check if directory listing is enabled by getting preamble
+        let preamble = std::fs::read_to_string(path.join(".directory-listing-ok"));
+        let preamble = match listing {
+            config::DirectoryListing::File => preamble.ok(),
+            config::DirectoryListing::Always => Some(preamble.unwrap_or_default()),
+            config::DirectoryListing::Never => None,
+        };
+        let Some(preamble) = preamble else {
+            self.send_error(
+                NOT_FOUND,
+                path,
+                ErrorMessage::NotFound,
+                "Directory index disabled.",
+            )
+            .await?;
             return Ok(());
         };
 
@@ -791,35 +1883,53 @@ where
         self.send_header(SUCCESS, "text/gemini").await?;
         self.stream.write_all(preamble.as_bytes()).await?;
 
-        let mut entries = tokio::fs::read_dir(path).await?;
-        let mut lines = vec![];
-        while let Some(entry) = entries.next_entry().await? {
-            let mut name = entry
-                .file_name()
-                .into_string()
-                .or(Err("Non-Unicode filename"))?;
-            if name.starts_with('.') {
-                continue;
-            }
-            if entry.file_type().await?.is_dir() {
-                name += "/";
-            }
-            let line = match percent_encode(name.as_bytes(), &ENCODE_SET).into() {
-                Cow::Owned(url) => format!("=> {url} {name}\n"),
-                Cow::Borrowed(url) => format!("=> {url}\n"), // url and name are identical
-            };
-            lines.push(line);
-        }
-        lines.sort();
-        for line in lines {
-            self.stream.write_all(line.as_bytes()).await?;
-        }
+        let listing = match &ARGS.cache {
+            Some(cache) => {
+                let read_at = SystemTime::now();
+                let metadata = tokio::fs::metadata(path).await?;
+                // listings do not depend on the sidecar files
+                match cache.get(cache::Kind::Listing, path, &metadata, 0) {
+                    Some(cached) => cached.data,
+                    None => {
+                        let cached = cache::Cached {
+                            data: read_listing(path).await?.into_bytes().into(),
+                            header: "text/gemini".into(),
+                        };
+                        cache.insert(
+                            cache::Kind::Listing,
+                            path,
+                            &metadata,
+                            read_at,
+                            0,
+                            cached.clone(),
+                        );
+                        cached.data
+                    }
+                }
+            }
+            None => read_listing(path).await?.into_bytes().into(),
+        };
+        self.stream.write_all(&listing).await?;
         Ok(())
     }
 
+    /// Sends an error header with the message set for the path in the `.meta`
+    /// files, or the default message.
+    async fn send_error(
+        &mut self,
+        status: u8,
+        path: &Path,
+        kind: ErrorMessage,
+        default: &str,
+    ) -> Result {
+        let message = self.metadata.error_message(path, kind).await;
+        self.send_header(status, message.as_deref().unwrap_or(default))
+            .await
+    }
+
     async fn send_header(&mut self, status: u8, meta: &str) -> Result {
-        // add response status and response meta
-        write!(self.log_line, " {status} \"{meta}\"")?;
+        self.entry.status = Some(status);
+        self.entry.meta = Some(meta.into());
 
         self.stream
             .write_all(format!("{status} {meta}\r\n").as_bytes())
@@ -1,10 +1,16 @@
 use configparser::ini::Ini;
-use glob::{MatchOptions, glob_with};
+use glob::{MatchOptions, Pattern};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher, event::ModifyKind};
+use regex::Regex;
 use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
+use std::sync::{
+    Arc, Mutex, RwLock,
+    atomic::{AtomicBool, AtomicU64, Ordering},
+};
 use std::time::SystemTime;
 
-static SIDECAR_FILENAME: &str = ".meta";
+pub(crate) static SIDECAR_FILENAME: &str = ".meta";
 
 /// A struct to store a string of metadata for each file retrieved from
 /// sidecar files with the name given by `SIDECAR_FILENAME`.
@@ -13,24 +19,88 @@ static SIDECAR_FILENAME: &str = ".meta";
 /// ```text
 /// <filename>:<metadata>
 /// ```
-/// where `<filename>` is only a filename (not a path) of a file that resides
-/// in the same directory and `<metadata>` is the metadata to be stored.
+/// where `<filename>` is the path of a file relative to the directory of the
+/// sidecar file, which may lead into subdirectories, and `<metadata>` is the
+/// metadata to be stored.
 /// Lines that start with optional whitespace and `#` are ignored, as are lines
 /// that do not fit the basic format.
 /// Both parts are stripped of any leading and/or trailing whitespace.
+/// Files listed in a `[cgi]` section are marked as CGI scripts and entries in a
+/// `[client-certs]` section restrict access to files and directories to
+/// clients with certificates. Entries in a `[titan]` section allow uploading
+/// files with the Titan protocol. Entries in a `[redirects]` section redirect
+/// requests for matching paths, which do not have to exist. Entries in an
+/// `[errors]` section change the messages of error responses.
+///
+/// Unless a central sidecar file is used, the sidecar files of all directories
+/// from the one of the file up to the content root apply to a file, and the
+/// nearest one with a matching entry is used.
+///
+/// The parsed sidecar files are shared by all connections. The lock is only
+/// held to look up or replace a parsed file, sidecar files are read without
+/// holding it, so requests do not wait for each other.
+///
+/// Sidecar files are checked for changes by their modification time on every
+/// request, unless the content directories are watched for changes.
 pub(crate) struct FileOptions {
-    /// Stores the paths of the side files and when they were last read.
-    /// By comparing this to the last write time, we can know if the file
-    /// has changed.
-    databases_read: BTreeMap<PathBuf, SystemTime>,
-    /// Stores the metadata for each file
-    file_meta: BTreeMap<PathBuf, PresetMeta>,
-    /// The default value to return
-    default: PresetMeta,
+    /// Stores the parsed side files by their path. `None` means that there
+    /// was no sidecar file, which is only stored while watching for changes
+    /// if the directory exists, or if there was one before.
+    databases: RwLock<BTreeMap<PathBuf, Option<Arc<Database>>>>,
+    /// If the content directories are watched, so parsed sidecar files are
+    /// removed when they change and do not have to be checked.
+    watched: AtomicBool,
+    /// Counts up whenever parsed sidecar files are removed because of a change,
+    /// so files that were read during a change are not stored.
+    generation: AtomicU64,
+    /// Counts up whenever a parsed sidecar file is stored or removed, so
+    /// anything derived from the entries can be checked for changes.
+    version: AtomicU64,
+}
+
+/// The entries of a single sidecar file.
+struct Database {
+    /// The directory of the sidecar file.
+    dir: PathBuf,
+    /// When the file was read. By comparing this to the last write time, we
+    /// can know if the file has changed.
+    read_at: SystemTime,
+    /// Stores the metadata for files
+    presets: Vec<Rule<PresetMeta>>,
+    /// Stores the entries of the `[cgi]` section, which take precedence over
+    /// the other presets
+    cgi: Vec<Rule<()>>,
+    /// Stores the client certificate requirements for files and directories
+    cert_requirements: Vec<Rule<CertRequirement>>,
+    /// Stores the patterns of paths that can be uploaded to
+    upload_permissions: Vec<Rule<UploadPermission>>,
+    /// Stores the redirect rules, which are matched against relative paths
+    /// with their own syntax.
+    redirects: Vec<Redirect>,
+    /// Stores the messages for error responses.
+    error_messages: BTreeMap<ErrorMessage, String>,
+}
+
+/// An entry of a sidecar file, whose path is a glob pattern that is matched
+/// against requested paths, so it also applies to files created after the
+/// sidecar file was read.
+struct Rule<T> {
+    /// The pattern, including the escaped directory of the sidecar file.
+    pattern: Pattern,
+    /// If the entry has no wildcards, since such entries take precedence.
+    exact: bool,
+    value: T,
 }
 
 /// A struct to store the different alternatives that a line in the sidecar
 /// file can have.
+///
+/// The path of a line may be a glob pattern, which is matched against the
+/// requested path. If several lines of a sidecar file match, the most
+/// specific one is used: an entry in the `[cgi]` section, then a path without
+/// wildcards, then the longest pattern. Patterns of the same length are
+/// compared as strings, so the result does not depend on the order of the
+/// lines. Entries for directories are ignored.
 #[derive(Clone, Debug)]
 pub(crate) enum PresetMeta {
     /// A line that starts with a semicolon in the sidecar file, or an
@@ -55,93 +125,654 @@ pub(crate) enum PresetMeta {
     /// gone.gmi: 52 This file is no longer available.
     /// ```
     /// Agate will send this header line, CR, LF, and nothing else. Agate will
-    /// not try to access the requested file.
+    /// not try to access the requested file. A line may only consist of a
+    /// status code from 40 to 69, then the message from the `[errors]`
+    /// section is used for the status codes listed in `ErrorMessage`.
     FullHeader(u8, String),
+    /// A key in the `[cgi]` section of the sidecar file. The value is ignored.
+    /// ```text
+    /// [cgi]
+    /// guestbook.sh:
+    /// ```
+    /// Agate will run the respective file as a CGI script, even if CGI
+    /// scripts are not enabled for all executable files.
+    Cgi,
+}
+
+/// Which client certificates may access a file or directory, from a line in
+/// the `[client-certs]` section of the sidecar file.
+/// ```text
+/// [client-certs]
+/// members/**:
+/// admin.gmi: 0f3c...e1a2 7b9d...04c5
+/// ```
+#[derive(Clone, Debug)]
+pub(crate) enum CertRequirement {
+    /// A line with an empty value: any client certificate is accepted.
+    Any,
+    /// A line with a whitespace separated list of SHA-256 fingerprints in
+    /// hexadecimal, optionally with colons between the bytes: only client
+    /// certificates with one of these fingerprints are accepted.
+    Fingerprints(Vec<String>),
+}
+
+impl CertRequirement {
+    fn parse(value: &str) -> Self {
+        if value.trim().is_empty() {
+            return Self::Any;
+        }
+
+        Self::Fingerprints(
+            value
+                .split_whitespace()
+                .filter_map(|fingerprint| {
+                    let normalized = fingerprint.replace(':', "").to_ascii_lowercase();
+                    if normalized.len() == 64 && normalized.bytes().all(|b| b.is_ascii_hexdigit()) {
+                        Some(normalized)
+                    } else {
+                        // This does not fall back to accepting any certificate,
+                        // so a typo can not make a resource public by accident.
+                        log::error!("ignoring invalid certificate fingerprint {fingerprint:?}");
+                        None
+                    }
+                })
+                .collect(),
+        )
+    }
+
+    /// Checks if a client certificate with the given fingerprint meets this
+    /// requirement.
+    pub fn allows(&self, fingerprint: &str) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Fingerprints(allowed) => allowed.iter().any(|f| f == fingerprint),
+        }
+    }
+}
+
+/// Who may upload files with the Titan protocol, from a line in the `[titan]`
+/// section of the sidecar file.
+/// ```text
+/// [titan]
+/// notes/*.gmi:
+/// inbox/*: token:s3cret 0f3c...e1a2
+/// ```
+#[derive(Clone, Debug)]
+pub(crate) struct UploadPermission {
+    /// The tokens that are accepted from the `token` parameter of the upload,
+    /// given as `token:<token>` in the sidecar file.
+    tokens: Vec<String>,
+    /// The client certificates that are accepted, in the same format as in
+    /// the `[client-certs]` section. If the value only consists of tokens, no
+    /// client certificates are accepted. If it is empty, any client
+    /// certificate is accepted.
+    certs: Option<CertRequirement>,
+}
+
+impl UploadPermission {
+    fn parse(value: &str) -> Self {
+        let (tokens, fingerprints): (Vec<&str>, Vec<&str>) = value
+            .split_whitespace()
+            .partition(|item| item.starts_with("token:"));
+        let tokens: Vec<String> = tokens
+            .into_iter()
+            .map(|token| token["token:".len()..].to_string())
+            .collect();
+
+        let certs = if fingerprints.is_empty() && !tokens.is_empty() {
+            None
+        } else {
+            Some(CertRequirement::parse(&fingerprints.join(" ")))
+        };
+
+        Self { tokens, certs }
+    }
+
+    /// Checks if an upload with the given token (if any) is allowed.
+    pub fn allows_token(&self, token: Option<&str>) -> bool {
+        token.is_some_and(|token| self.tokens.iter().any(|t| t == token))
+    }
+
+    /// Returns the requirement for client certificates that can be used
+    /// instead of a token, if client certificates are accepted.
+    pub fn cert_requirement(&self) -> Option<&CertRequirement> {
+        self.certs.as_ref()
+    }
+}
+
+/// A rule from the `[redirects]` section of the sidecar file. The key is the
+/// path relative to the directory of the sidecar file, which may be a glob
+/// pattern, or a regular expression if it starts with `~`. The value is the
+/// status code (30 or 31, 31 if it is left out) and the target, which is
+/// either an absolute path on the same host or a URL with a scheme.
+/// ```text
+/// [redirects]
+/// old/**: 31 /new/$1
+/// ~posts/(\d+)\.gmi: gemini://blog.example.com/${1}.gmi
+/// moved.gmi: 30 /elsewhere.gmi
+/// ```
+/// Each wildcard of a glob pattern (`*`, `**` or `?`) is a capture group, the
+/// captures can be used in the target as `$1`, `${1}` and so on.
+#[derive(Clone, Debug)]
+pub(crate) struct Redirect {
+    source: Regex,
+    /// If the source is a path without wildcards, which takes precedence.
+    exact: bool,
+    /// The length of the source as written, longer sources are preferred.
+    len: usize,
+    status: u8,
+    target: String,
+}
+
+impl Redirect {
+    fn parse(source: &str, value: &str) -> Result<Self, String> {
+        let (status, target) = match *value.split_whitespace().collect::<Vec<_>>() {
+            [target] if !target.starts_with(|c: char| c.is_ascii_digit()) => (31, target),
+            ["30", target] => (30, target),
+            ["31", target] => (31, target),
+            [status, _] if status.starts_with(|c: char| c.is_ascii_digit()) => {
+                return Err("the status has to be 30 or 31".into());
+            }
+            [] | [_] => return Err("the target is missing".into()),
+            // keys end at the first `:` or `=`, so the rest of a source that
+            // contains one of them ends up in the value
+            _ => {
+                return Err(
+                    "the value has to be a status and a target; if the source contains `:` or `=`, use a regular expression with `\\x3A` or `\\x3D` instead"
+                        .into(),
+                );
+            }
+        };
+        if !target.starts_with('/') && !target.contains("://") {
+            return Err("the target has to be an absolute path or a URL".into());
+        }
+
+        let (exact, regex) = match source.strip_prefix('~') {
+            Some(regex) => (false, format!("^(?:{regex})$")),
+            None => (
+                !source.contains(['*', '?']),
+                glob_to_regex(source.trim_start_matches('/')),
+            ),
+        };
+        let source_regex = Regex::new(&regex).map_err(|e| e.to_string())?;
+
+        Ok(Self {
+            source: source_regex,
+            exact,
+            len: source.len(),
+            status,
+            target: target.into(),
+        })
+    }
+
+    /// Returns the status and target if the rule matches the path, which is
+    /// relative to the directory of the sidecar file.
+    fn apply(&self, path: &str) -> Option<(u8, String)> {
+        let captures = self.source.captures(path)?;
+        let mut target = String::new();
+        captures.expand(&self.target, &mut target);
+        Some((self.status, target))
+    }
+}
+
+/// Converts a glob pattern to an anchored regular expression with a capture
+/// group for each wildcard.
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.next_if_eq(&'*').is_some() => regex.push_str("(.*)"),
+            '*' => regex.push_str("([^/]*)"),
+            '?' => regex.push_str("([^/])"),
+            c => regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+/// The error responses whose message can be changed in the `[errors]`
+/// section of the sidecar file. The messages apply to the directory of the
+/// sidecar file and its subdirectories.
+/// ```text
+/// [errors]
+/// 51: Nicht gefunden.
+/// secret: Das ist geheim.
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum ErrorMessage {
+    /// The key `51`: a file was not found or a directory can not be listed.
+    NotFound,
+    /// The key `52`, only used by presets that only consist of the status.
+    Gone,
+    /// The key `59`: uploading is not allowed.
+    BadRequest,
+    /// The key `secret`: a hidden file was requested, which is answered with
+    /// status 52.
+    Secret,
+}
+
+impl ErrorMessage {
+    fn from_key(key: &str) -> Option<Self> {
+        match key {
+            "51" => Some(Self::NotFound),
+            "52" => Some(Self::Gone),
+            "59" => Some(Self::BadRequest),
+            "secret" => Some(Self::Secret),
+            _ => None,
+        }
+    }
+
+    /// Returns the message for a response with the status code, if its
+    /// message can be changed.
+    pub fn for_status(status: u8) -> Option<Self> {
+        match status {
+            51 => Some(Self::NotFound),
+            52 => Some(Self::Gone),
+            59 => Some(Self::BadRequest),
+            _ => None,
+        }
+    }
 }
 
 impl FileOptions {
-    pub(crate) fn new(default: PresetMeta) -> Self {
+    pub(crate) fn new() -> Self {
         Self {
-            databases_read: BTreeMap::new(),
-            file_meta: BTreeMap::new(),
-            default,
+            databases: RwLock::new(BTreeMap::new()),
+            watched: AtomicBool::new(false),
+            generation: AtomicU64::new(0),
+            version: AtomicU64::new(0),
         }
     }
 
-    /// Checks wether the database for the directory of the specified file is
-    /// still up to date and re-reads it if outdated or not yet read.
-    fn update(&mut self, file: &Path) {
-        let mut db = if super::ARGS.central_config {
-            super::ARGS.content_dir.clone()
-        } else {
-            file.parent().expect("no parent directory").to_path_buf()
+    /// Starts watching the content directories for changes. Parsed sidecar
+    /// files are then removed when they change, or when a directory above them
+    /// is created, removed or renamed, and are no longer checked for changes
+    /// on every request. Symbolic links are not followed, so changes to
+    /// sidecar files in linked directories are not noticed. Watching stops
+    /// when the returned watcher is dropped.
+    pub fn watch(self: &Arc<Self>) -> notify::Result<RecommendedWatcher> {
+        let options = Arc::downgrade(self);
+        let mut watcher = notify::recommended_watcher(move |event| {
+            if let Some(options) = options.upgrade() {
+                options.changed(event);
+            }
+        })?;
+        for root in super::ARGS.vhosts.roots() {
+            watcher.watch(root, RecursiveMode::Recursive)?;
+        }
+        self.watched.store(true, Ordering::SeqCst);
+        Ok(watcher)
+    }
+
+    /// Removes the parsed sidecar files that are affected by a change.
+    fn changed(&self, event: notify::Result<Event>) {
+        let event = match event {
+            Ok(event) if !event.need_rescan() => event,
+            Ok(_) => {
+                log::debug!(
+                    "missed changes in the content directories, forgetting all .meta files"
+                );
+                return self.remove(|_| true);
+            }
+            Err(e) => {
+                log::warn!(
+                    "error watching the content directories, forgetting all .meta files: {e}"
+                );
+                return self.remove(|_| true);
+            }
         };
-        db.push(SIDECAR_FILENAME);
-
-        let should_read = if let Ok(metadata) = db.metadata() {
-            if !metadata.is_file() {
-                // it exists, but it is a directory
-                false
-            } else if let (Ok(modified), Some(last_read)) =
-                (metadata.modified(), self.databases_read.get(&db))
-            {
-                // check that it was last modified before the read
-                // if the times are the same, we might have read the old file
-                &modified >= last_read
-            } else {
-                // either the filesystem does not support last modified
-                // metadata, so we have to read it again every time; or the
-                // file exists but was not read before, so we have to read it
-                true
+
+        if matches!(event.kind, EventKind::Access(_) | EventKind::Other) {
+            return;
+        }
+        // a directory that was created, removed or renamed might contain
+        // other sidecar files than before, so they are looked up again
+        let listing_changed = matches!(
+            event.kind,
+            EventKind::Create(_) | EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(_))
+        );
+        for path in event.paths {
+            if path.file_name() == Some(SIDECAR_FILENAME.as_ref()) {
+                log::debug!("{path:?} changed");
+                self.remove(|db| db == path);
+            } else if listing_changed {
+                self.remove(|db| db.starts_with(&path));
+            }
+        }
+    }
+
+    /// Removes the parsed sidecar files whose path matches, and that there
+    /// are none.
+    fn remove(&self, matches: impl Fn(&Path) -> bool) {
+        let mut databases = self.databases.write().unwrap();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        let mut removed = false;
+        databases.retain(|db, database| {
+            let matches = matches(db);
+            removed |= matches && database.is_some();
+            !matches
+        });
+        if removed {
+            self.version.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Returns the version of the parsed sidecar files. If it is the same as
+    /// before, the entries for all paths whose sidecar files were (re-)loaded
+    /// in the meantime are the same.
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::SeqCst)
+    }
+
+    /// Returns the database for the specified sidecar file, after (re-)reading
+    /// it if necessary.
+    async fn load(&self, db: PathBuf) -> Option<Arc<Database>> {
+        let watched = self.watched.load(Ordering::SeqCst);
+        let generation = self.generation.load(Ordering::SeqCst);
+        let current = self.databases.read().unwrap().get(&db).cloned();
+        if watched && let Some(current) = current {
+            // it would have been removed if it changed
+            return current;
+        }
+
+        // the file probably does not exist, or it is a directory
+        let Some(metadata) = tokio::fs::metadata(&db)
+            .await
+            .ok()
+            .filter(|metadata| metadata.is_file())
+        else {
+            // only remember that there is none for directories that exist, so
+            // requests for made up paths do not fill the map; also forget a
+            // sidecar file that was removed
+            let dir_exists = watched
+                && tokio::fs::metadata(parent(&db))
+                    .await
+                    .is_ok_and(|metadata| metadata.is_dir());
+            if dir_exists || matches!(current, Some(Some(_))) {
+                self.store(db, None, generation);
             }
+            return None;
+        };
+
+        if let (Ok(modified), Some(Some(current))) = (metadata.modified(), current) {
+            // check that it was last modified before the read
+            // if the times are the same, we might have read the old file
+            if modified < current.read_at {
+                return Some(current);
+            }
+        }
+        // either the filesystem does not support last modified metadata, so
+        // we have to read it again every time; or the file changed or was not
+        // read before, so we have to read it
+
+        let path = db.clone();
+        let database = tokio::task::spawn_blocking(move || Database::read(&path))
+            .await
+            .expect("reading sidecar file panicked");
+        self.store(db, Some(Arc::new(database)), generation)
+    }
+
+    /// Stores a parsed sidecar file, or that there is none, unless it was
+    /// read before the last change. Returns the newest version.
+    fn store(
+        &self,
+        db: PathBuf,
+        database: Option<Arc<Database>>,
+        generation: u64,
+    ) -> Option<Arc<Database>> {
+        let mut databases = self.databases.write().unwrap();
+        if self.generation.load(Ordering::SeqCst) != generation {
+            // it might have changed while it was read
+            return database;
+        }
+        // another request might have read a newer version in the meantime
+        match (databases.get(&db), &database) {
+            (Some(Some(other)), Some(database)) if other.read_at > database.read_at => {
+                Some(other.clone())
+            }
+            _ => {
+                let old = databases.insert(db, database.clone());
+                if database.is_some() || matches!(old, Some(Some(_))) {
+                    // the entries for some paths might have changed
+                    self.version.fetch_add(1, Ordering::SeqCst);
+                }
+                database
+            }
+        }
+    }
+}
+
+/// The sidecar files used for a single request. Each sidecar file is only
+/// checked for changes once, so the lookups for a request do not have to check
+/// the same files again, and they all use the same version of them.
+pub(crate) struct RequestOptions {
+    options: Arc<FileOptions>,
+    /// The sidecar files that were looked up by their path, `None` if there
+    /// is none.
+    loaded: Mutex<BTreeMap<PathBuf, Option<Arc<Database>>>>,
+}
+
+impl RequestOptions {
+    pub fn new(options: Arc<FileOptions>) -> Self {
+        Self {
+            options,
+            loaded: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Returns the version of the parsed sidecar files, see
+    /// `FileOptions::version`.
+    pub fn version(&self) -> u64 {
+        self.options.version()
+    }
+
+    /// Returns the databases that apply to files in the directory `dir`, from
+    /// the nearest one up to the one in the content root, after (re-)reading
+    /// them if they are outdated or were not read yet. With a central
+    /// configuration file, only the one in the `config_root` of the host is
+    /// used.
+    async fn databases(&self, dir: &Path) -> Vec<Arc<Database>> {
+        let vhost = super::ARGS.vhosts.for_path(dir);
+        let dirs: Vec<&Path> = if vhost.central_config {
+            vec![&vhost.config_root]
         } else {
-            // the file probably does not exist
-            false
+            dir.ancestors()
+                .take_while(|dir| dir.starts_with(&vhost.config_root))
+                .collect()
         };
 
-        if should_read {
-            self.read_database(&db);
+        let mut databases = vec![];
+        for dir in dirs {
+            let db = dir.join(SIDECAR_FILENAME);
+            let loaded = self.loaded.lock().unwrap().get(&db).cloned();
+            let database = match loaded {
+                Some(database) => database,
+                None => {
+                    let database = self.options.load(db.clone()).await;
+                    self.loaded.lock().unwrap().insert(db, database.clone());
+                    database
+                }
+            };
+            databases.extend(database);
+        }
+        databases
+    }
+
+    /// Get the metadata for the specified file. This might need to (re)load
+    /// the sidecar files of its directory and the directories above it, the
+    /// nearest sidecar file with an entry for the file is used.
+    /// The file path should consistenly be either absolute or relative to the
+    /// working/content directory. If inconsistent file paths are used, this can
+    /// lead to loading and storing sidecar files multiple times.
+    pub async fn get(&self, file: &Path) -> PresetMeta {
+        match self.databases(parent(file)).await.iter().find_map(|db| {
+            Rule::matching(&db.cgi, file)
+                .map(|()| PresetMeta::Cgi)
+                .or_else(|| Rule::matching(&db.presets, file).cloned())
+        }) {
+            Some(preset) => preset,
+            // the default depends on the language of the host
+            None => crate::ARGS.vhosts.for_path(file).default_preset(),
+        }
+    }
+
+    /// Returns true if a configuration exists in a configuration file.
+    /// Returns false if no or only the default value exists, or if the path
+    /// is a directory, since entries for directories are ignored.
+    pub async fn exists(&self, file: &Path) -> bool {
+        if tokio::fs::metadata(file)
+            .await
+            .is_ok_and(|metadata| metadata.is_dir())
+        {
+            return false;
+        }
+        self.databases(parent(file)).await.iter().any(|db| {
+            Rule::matching(&db.cgi, file).is_some() || Rule::matching(&db.presets, file).is_some()
+        })
+    }
+
+    /// Returns the client certificate requirement for the specified file or
+    /// directory, if there is one.
+    pub async fn cert_requirement(&self, path: &Path) -> Option<CertRequirement> {
+        // the sidecar file of a directory can protect the directory itself
+        // with a `**` entry
+        self.databases(dir_of(path).await)
+            .await
+            .iter()
+            .find_map(|db| Rule::matching(&db.cert_requirements, path).cloned())
+    }
+
+    /// Returns who may upload a file to the specified path, if anyone may.
+    /// The nearest sidecar file with a matching entry is used.
+    pub async fn upload_permission(&self, path: &Path) -> Option<UploadPermission> {
+        self.databases(parent(path))
+            .await
+            .iter()
+            .find_map(|db| Rule::matching(&db.upload_permissions, path).cloned())
+    }
+
+    /// Returns the status and target of the redirect for the specified path,
+    /// which does not have to exist, if there is one. The nearest sidecar
+    /// file with a matching rule is used. If several rules in it match, a rule
+    /// without wildcards is preferred, otherwise the longest source is used.
+    pub async fn redirect(&self, path: &Path) -> Option<(u8, String)> {
+        for db in self.databases(parent(path)).await {
+            let Ok(relative) = path.strip_prefix(&db.dir) else {
+                continue;
+            };
+            let Some(relative) = relative
+                .components()
+                .map(|component| component.as_os_str().to_str())
+                .collect::<Option<Vec<_>>>()
+            else {
+                // paths that are not UTF-8 can not be matched
+                return None;
+            };
+            let relative = relative.join("/");
+
+            let redirect = db
+                .redirects
+                .iter()
+                .filter_map(|redirect| Some((redirect, redirect.apply(&relative)?)))
+                .max_by_key(|(redirect, _)| (redirect.exact, redirect.len))
+                .map(|(_, redirect)| redirect);
+            if redirect.is_some() {
+                return redirect;
+            }
         }
+        None
+    }
+
+    /// Returns the message for an error response for the specified path, if
+    /// one is set in the sidecar file of the path (if it is a directory) or of
+    /// the directories above it. The nearest sidecar file that sets the
+    /// message is used.
+    pub async fn error_message(&self, path: &Path, kind: ErrorMessage) -> Option<String> {
+        self.databases(dir_of(path).await)
+            .await
+            .iter()
+            .find_map(|db| db.error_messages.get(&kind).cloned())
+    }
+}
+
+fn parent(path: &Path) -> &Path {
+    path.parent().expect("no parent directory")
+}
+
+/// Returns the path itself if it is a directory, otherwise its parent.
+async fn dir_of(path: &Path) -> &Path {
+    if tokio::fs::metadata(path)
+        .await
+        .is_ok_and(|metadata| metadata.is_dir())
+    {
+        path
+    } else {
+        parent(path)
     }
+}
 
-    /// (Re)reads a specified sidecar file.
-    /// This function will allways try to read the file, even if it is current.
-    fn read_database(&mut self, db: &Path) {
+impl Database {
+    /// Reads a specified sidecar file. This blocks, so it should not run on
+    /// the async runtime directly.
+    fn read(db: &Path) -> Self {
         log::debug!("reading database {db:?}");
+        crate::metrics::METRICS.meta_reloaded();
+
+        let mut database = Self {
+            dir: db.parent().expect("no parent directory").to_path_buf(),
+            read_at: SystemTime::now(),
+            presets: vec![],
+            cgi: vec![],
+            cert_requirements: vec![],
+            upload_permissions: vec![],
+            redirects: vec![],
+            error_messages: BTreeMap::new(),
+        };
+        // the directory is part of the patterns, which have to be UTF-8
+        let Some(dir) = database.dir.to_str().map(str::to_string) else {
+            log::error!("path is not UTF-8: {:?}", database.dir);
+            return database;
+        };
 
         let mut ini = Ini::new_cs();
         ini.set_default_section("mime");
         ini.set_comment_symbols(&['#']);
-        let map = ini
-            .load(db.to_str().expect("config path not UTF-8"))
-            .and_then(|mut sections| {
-                sections
-                    .remove("mime")
-                    .ok_or_else(|| "no \"mime\" or default section".to_string())
-            });
-        self.databases_read
-            .insert(db.to_path_buf(), SystemTime::now());
-        let files = match map {
-            Ok(section) => section,
+        let map = ini.load(db.to_str().expect("config path not UTF-8"));
+        let mut sections = match map {
+            Ok(sections) => sections,
             Err(err) => {
                 log::error!("invalid config file {db:?}: {err}");
-                return;
+                return database;
             }
         };
-
-        for (rel_path, header) in files {
+        for (rel_path, header) in sections.remove("mime").unwrap_or_default() {
             // treat unassigned keys as if they had an empty value
             let header = header.unwrap_or_default();
 
-            // generate workspace-relative path
+            // the path of the entry, for log messages
             let mut path = db.to_path_buf();
             path.pop();
-            path.push(rel_path);
+            path.push(&rel_path);
 
             // parse the preset
             let preset = if header.is_empty() || header.starts_with(';') {
                 PresetMeta::Parameters(header.to_string())
             } else if matches!(header.chars().next(), Some('1'..='6')) {
+                if header.len() == 2
+                    && matches!(header.chars().next(), Some('4'..='6'))
+                    && header.chars().nth(1).unwrap().is_ascii_digit()
+                {
+                    // only the status code of an error, which does not need
+                    // a message or whose message is set elsewhere
+                    database.presets.extend(Rule::new(
+                        &dir,
+                        &rel_path,
+                        PresetMeta::FullHeader(header.parse().unwrap(), String::new()),
+                    ));
+                    continue;
+                }
                 if header.len() < 3
                     || !header.chars().nth(1).unwrap().is_ascii_digit()
                     || !header.chars().nth(2).unwrap().is_whitespace()
@@ -149,7 +780,7 @@ impl FileOptions {
                     log::error!(
                         "Line for {path:?} starts like a full header line, but it is incorrect; ignoring it."
                     );
-                    return;
+                    continue;
                 }
                 let separator = header.chars().nth(2).unwrap();
                 if separator != ' ' {
@@ -176,65 +807,133 @@ impl FileOptions {
                 PresetMeta::FullMime(header.to_string())
             };
 
-            let glob_options = MatchOptions {
-                case_sensitive: true,
-                // so there is a difference between "*" and "**".
-                require_literal_separator: true,
-                // security measure because entries for .hidden files
-                // would result in them being exposed.
-                require_literal_leading_dot: !crate::ARGS.serve_secret,
-            };
+            database.presets.extend(Rule::new(&dir, &rel_path, preset));
+        }
 
-            // process filename as glob
-            let paths = if let Some(path) = path.to_str() {
-                match glob_with(path, glob_options) {
-                    Ok(paths) => paths.collect::<Vec<_>>(),
-                    Err(err) => {
-                        log::error!("incorrect glob pattern in {path:?}: {err}");
-                        continue;
-                    }
-                }
-            } else {
-                log::error!("path is not UTF-8: {path:?}");
-                continue;
-            };
+        for rel_path in sections.remove("cgi").unwrap_or_default().into_keys() {
+            database.cgi.extend(Rule::new(&dir, &rel_path, ()));
+        }
 
-            if paths.is_empty() {
-                // probably an entry for a nonexistent file, glob only works for existing files
-                self.file_meta.insert(path, preset);
-            } else {
-                for glob_result in paths {
-                    match glob_result {
-                        Ok(path) if path.is_dir() => { /* ignore */ }
-                        Ok(path) => {
-                            self.file_meta.insert(path, preset.clone());
-                        }
-                        Err(err) => {
-                            log::warn!("could not process glob path: {err}");
-                            continue;
-                        }
-                    };
+        // these also match directories so directory listings can be protected
+        for (rel_path, value) in sections.remove("client-certs").unwrap_or_default() {
+            let requirement = CertRequirement::parse(&value.unwrap_or_default());
+            // a `**` pattern also protects the listing of its base directory
+            database
+                .cert_requirements
+                .extend(Rule::base_dir(&dir, &rel_path, requirement.clone()));
+            database
+                .cert_requirements
+                .extend(Rule::new(&dir, &rel_path, requirement));
+        }
+
+        for (source, value) in sections.remove("redirects").unwrap_or_default() {
+            match Redirect::parse(&source, &value.unwrap_or_default()) {
+                Ok(redirect) => database.redirects.push(redirect),
+                Err(err) => log::error!("invalid redirect for {source:?} in {db:?}: {err}"),
+            }
+        }
+
+        for (key, message) in sections.remove("errors").unwrap_or_default() {
+            match ErrorMessage::from_key(&key) {
+                Some(kind) => {
+                    database
+                        .error_messages
+                        .insert(kind, message.unwrap_or_default());
                 }
+                None => log::error!("unknown error message {key:?} in {db:?}"),
             }
         }
+
+        for (rel_path, value) in sections.remove("titan").unwrap_or_default() {
+            let permission = UploadPermission::parse(&value.unwrap_or_default());
+            database
+                .upload_permissions
+                .extend(Rule::new(&dir, &rel_path, permission));
+        }
+
+        // client certificates are only asked for once something needs them
+        if !database.cert_requirements.is_empty()
+            || !database.cgi.is_empty()
+            || database
+                .upload_permissions
+                .iter()
+                .any(|rule| rule.value.certs.is_some())
+        {
+            crate::certificates::request_client_certs();
+        }
+        database
     }
+}
 
-    /// Get the metadata for the specified file. This might need to (re)load a
-    /// single sidecar file.
-    /// The file path should consistenly be either absolute or relative to the
-    /// working/content directory. If inconsistent file paths are used, this can
-    /// lead to loading and storing sidecar files multiple times.
-    pub fn get(&mut self, file: &Path) -> PresetMeta {
-        self.update(file);
+impl<T> Rule<T> {
+    /// Compiles the pattern of an entry in the sidecar file of `dir`. Returns
+    /// `None` and logs an error if the pattern is incorrect.
+    fn new(dir: &str, rel_path: &str, value: T) -> Option<Self> {
+        // the directory may contain characters that are special in glob
+        // patterns, the entry itself is a pattern
+        let pattern = format!("{}/{rel_path}", Pattern::escape(dir));
+        match Pattern::new(&pattern) {
+            Ok(pattern) => Some(Self {
+                pattern,
+                exact: Pattern::escape(rel_path) == rel_path,
+                value,
+            }),
+            Err(err) => {
+                log::error!("incorrect glob pattern in {pattern:?}: {err}");
+                None
+            }
+        }
+    }
 
-        self.file_meta.get(file).unwrap_or(&self.default).clone()
+    /// Returns a rule for the directory that an entry ending in `**` applies
+    /// to, e.g. `members` for `members/**`, if the entry is one. Since the
+    /// pattern does not match the directory itself, the rule is not exact, so
+    /// an entry for the directory takes precedence.
+    fn base_dir(dir: &str, rel_path: &str, value: T) -> Option<Self> {
+        let pattern = match rel_path.strip_suffix("**")? {
+            "" => Pattern::escape(dir),
+            base => format!("{}/{}", Pattern::escape(dir), base.strip_suffix('/')?),
+        };
+        match Pattern::new(&pattern) {
+            Ok(pattern) => Some(Self {
+                pattern,
+                exact: false,
+                value,
+            }),
+            // the error is logged for the entry itself
+            Err(_) => None,
+        }
     }
 
-    /// Returns true if a configuration exists in a configuration file.
-    /// Returns false if no or only the default value exists.
-    pub fn exists(&mut self, file: &Path) -> bool {
-        self.update(file);
+    /// Returns the value of the most specific rule that matches the path: a
+    /// rule without wildcards is preferred, otherwise the longest pattern is
+    /// used. Patterns of the same length are compared as strings, so the
+    /// result does not depend on the order of the rules.
+    fn matching<'a>(rules: &'a [Self], path: &Path) -> Option<&'a T> {
+        let options = match_options(path);
+        rules
+            .iter()
+            .filter(|rule| rule.pattern.matches_path_with(path, options))
+            .max_by_key(|rule| {
+                (
+                    rule.exact,
+                    rule.pattern.as_str().len(),
+                    rule.pattern.as_str(),
+                )
+            })
+            .map(|rule| &rule.value)
+    }
+}
 
-        self.file_meta.contains_key(file)
+/// The options used for matching glob patterns in sidecar files, which depend
+/// on the host the path belongs to.
+fn match_options(path: &Path) -> MatchOptions {
+    MatchOptions {
+        case_sensitive: true,
+        // so there is a difference between "*" and "**".
+        require_literal_separator: true,
+        // security measure because entries for .hidden files
+        // would result in them being exposed.
+        require_literal_leading_dot: !crate::ARGS.vhosts.for_path(path).serve_secret,
     }
 }
@@ -0,0 +1,316 @@
+use {
+    crate::accesslog::Entry,
+    std::{
+        fmt::{self, Write},
+        io,
+        net::SocketAddr,
+        path::PathBuf,
+        str::FromStr,
+        sync::atomic::{AtomicU64, Ordering::Relaxed},
+        time::Duration,
+    },
+    tokio::{
+        io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+        net::TcpListener,
+        time::timeout,
+    },
+};
+
+#[cfg(unix)]
+use {std::os::unix::fs::FileTypeExt, tokio::net::UnixListener};
+
+/// The upper bounds of the request duration histogram buckets, in seconds.
+const DURATION_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// The counters since agate was started.
+pub(crate) static METRICS: Metrics = Metrics::new();
+
+#[derive(Debug)]
+pub(crate) struct Metrics {
+    connections: AtomicU64,
+    open_connections: AtomicU64,
+    tls_handshake_failures: AtomicU64,
+    /// The number of requests by response status.
+    requests: [AtomicU64; 100],
+    bytes_sent: AtomicU64,
+    /// The number of requests that took at most the duration of the bucket
+    /// with the same index, but longer than the previous one.
+    durations: [AtomicU64; DURATION_BUCKETS.len() + 1],
+    duration_sum_micros: AtomicU64,
+    meta_reloads: AtomicU64,
+}
+
+/// Counts a connection as open until it is dropped.
+pub(crate) struct OpenConnection(());
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        METRICS.open_connections.fetch_sub(1, Relaxed);
+    }
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            connections: AtomicU64::new(0),
+            open_connections: AtomicU64::new(0),
+            tls_handshake_failures: AtomicU64::new(0),
+            requests: [const { AtomicU64::new(0) }; 100],
+            bytes_sent: AtomicU64::new(0),
+            durations: [const { AtomicU64::new(0) }; DURATION_BUCKETS.len() + 1],
+            duration_sum_micros: AtomicU64::new(0),
+            meta_reloads: AtomicU64::new(0),
+        }
+    }
+
+    /// Counts an accepted connection, which has to be kept open until the
+    /// connection is closed.
+    pub fn connection_accepted(&self) -> OpenConnection {
+        self.connections.fetch_add(1, Relaxed);
+        self.open_connections.fetch_add(1, Relaxed);
+        OpenConnection(())
+    }
+
+    /// Counts a connection that failed before a request could be received.
+    pub fn connection_failed(&self, entry: &Entry) {
+        // the special status code for TLS errors
+        if entry.status == Some(0) {
+            self.tls_handshake_failures.fetch_add(1, Relaxed);
+        }
+    }
+
+    /// Counts a request that was handled.
+    pub fn request_handled(&self, entry: &Entry) {
+        if let Some(status) = entry.status {
+            self.requests[usize::from(status).min(99)].fetch_add(1, Relaxed);
+        }
+        self.bytes_sent.fetch_add(entry.bytes_sent, Relaxed);
+
+        let duration = entry.duration();
+        let bucket = DURATION_BUCKETS
+            .iter()
+            .position(|&bound| duration.as_secs_f64() <= bound)
+            .unwrap_or(DURATION_BUCKETS.len());
+        self.durations[bucket].fetch_add(1, Relaxed);
+        self.duration_sum_micros.fetch_add(
+            u64::try_from(duration.as_micros()).unwrap_or(u64::MAX),
+            Relaxed,
+        );
+    }
+
+    /// Counts reading a `.meta` file.
+    pub fn meta_reloaded(&self) {
+        self.meta_reloads.fetch_add(1, Relaxed);
+    }
+
+    /// Formats the metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, values: &[(String, String)]| {
+            writeln!(out, "# HELP agate_{name} {help}").unwrap();
+            writeln!(out, "# TYPE agate_{name} {kind}").unwrap();
+            for (labels, value) in values {
+                writeln!(out, "agate_{name}{labels} {value}").unwrap();
+            }
+        };
+        let value = |counter: &AtomicU64| vec![(String::new(), counter.load(Relaxed).to_string())];
+
+        metric(
+            "connections_total",
+            "counter",
+            "Connections accepted.",
+            &value(&self.connections),
+        );
+        metric(
+            "open_connections",
+            "gauge",
+            "Connections that are currently open.",
+            &value(&self.open_connections),
+        );
+        metric(
+            "tls_handshake_failures_total",
+            "counter",
+            "Connections where the TLS handshake failed.",
+            &value(&self.tls_handshake_failures),
+        );
+        let requests = self
+            .requests
+            .iter()
+            .enumerate()
+            .map(|(status, counter)| (status, counter.load(Relaxed)))
+            .filter(|(_, count)| *count > 0)
+            .map(|(status, count)| (format!("{{status=\"{status:02}\"}}"), count.to_string()))
+            .collect::<Vec<_>>();
+        metric(
+            "requests_total",
+            "counter",
+            "Requests by response status.",
+            &requests,
+        );
+        metric(
+            "sent_bytes_total",
+            "counter",
+            "Bytes sent in responses.",
+            &value(&self.bytes_sent),
+        );
+
+        // histogram buckets are cumulative
+        let mut count = 0;
+        let mut durations = vec![];
+        for (i, counter) in self.durations.iter().enumerate() {
+            count += counter.load(Relaxed);
+            let bound = DURATION_BUCKETS
+                .get(i)
+                .map_or("+Inf".into(), |bound| bound.to_string());
+            durations.push((format!("_bucket{{le=\"{bound}\"}}"), count.to_string()));
+        }
+        let sum = Duration::from_micros(self.duration_sum_micros.load(Relaxed));
+        durations.push(("_sum".into(), sum.as_secs_f64().to_string()));
+        durations.push(("_count".into(), count.to_string()));
+        metric(
+            "request_duration_seconds",
+            "histogram",
+            "Time from accepting the connection until the response was sent.",
+            &durations,
+        );
+
+        metric(
+            "meta_reloads_total",
+            "counter",
+            "Times a .meta file was read.",
+            &value(&self.meta_reloads),
+        );
+        out
+    }
+}
+
+/// Where the metrics are served.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Address {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for Address {
+    type Err = String;
+
+    /// Parses a socket address, or a path to a Unix socket if it contains a
+    /// slash.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse() {
+            Ok(Self::Tcp(addr))
+        } else if cfg!(unix) && s.contains('/') {
+            Ok(Self::Unix(s.into()))
+        } else {
+            Err(format!(
+                "invalid metrics address {s:?}, expected an IP address and port or a path containing a slash"
+            ))
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => addr.fmt(f),
+            Self::Unix(path) => path.display().fmt(f),
+        }
+    }
+}
+
+/// The listener for HTTP requests for the metrics.
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    pub async fn bind(address: &Address) -> io::Result<Self> {
+        match address {
+            Address::Tcp(addr) => Ok(Self::Tcp(TcpListener::bind(addr).await?)),
+            #[cfg(unix)]
+            Address::Unix(path) => {
+                // remove a socket left over from an earlier run
+                if path
+                    .symlink_metadata()
+                    .is_ok_and(|metadata| metadata.file_type().is_socket())
+                {
+                    std::fs::remove_file(path)?;
+                }
+                Ok(Self::Unix(UnixListener::bind(path)?))
+            }
+            #[cfg(not(unix))]
+            Address::Unix(_) => unreachable!("Unix sockets are not supported"),
+        }
+    }
+
+    /// Accepts connections and answers them.
+    pub async fn run(self) {
+        loop {
+            let result = match &self {
+                Self::Tcp(listener) => listener
+                    .accept()
+                    .await
+                    .map(|(stream, _)| tokio::spawn(serve(stream))),
+                #[cfg(unix)]
+                Self::Unix(listener) => listener
+                    .accept()
+                    .await
+                    .map(|(stream, _)| tokio::spawn(serve(stream))),
+            };
+            if let Err(e) = result {
+                log::warn!("Could not accept connection for metrics: {e}");
+            }
+        }
+    }
+}
+
+/// Answers an HTTP request on the metrics listener.
+async fn serve<S>(mut stream: S)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if let Err(e) = respond(&mut stream).await {
+        log::debug!("Could not answer metrics request: {e}");
+    }
+}
+
+async fn respond<S>(mut stream: S) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // read the request head, which is not needed except for the path
+    let mut head = vec![];
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > 16 * 1024 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request too large",
+            ));
+        }
+        let read = timeout(Duration::from_secs(10), stream.read(&mut buf))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "request timed out"))??;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        head.extend_from_slice(&buf[..read]);
+    }
+
+    let request_line = head.split(|&b| b == b'\r').next().unwrap_or_default();
+    let (status, body) = match request_line.split(|&b| b == b' ').collect::<Vec<_>>()[..] {
+        [b"GET", b"/metrics", _] => ("200 OK", METRICS.render()),
+        [b"GET", ..] => ("404 Not Found", "Not found, try /metrics\n".into()),
+        _ => ("405 Method Not Allowed", "Only GET is supported\n".into()),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
@@ -0,0 +1,142 @@
+use {
+    std::{
+        io,
+        net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    },
+    tokio::io::{AsyncRead, AsyncReadExt},
+};
+
+/// The signature at the start of a version 2 header.
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// The maximum length of a version 1 header, including the CRLF.
+const V1_MAX_LENGTH: usize = 107;
+
+/// The addresses of a connection that was forwarded by a proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Addresses {
+    /// The address of the client.
+    pub source: SocketAddr,
+    /// The address the client connected to.
+    pub destination: SocketAddr,
+}
+
+/// Reads a PROXY protocol header of version 1 or 2, as sent by HAProxy and
+/// other load balancers, from the start of the stream. Nothing after the
+/// header is read. Returns `None` if the proxy does not know the addresses or
+/// made the connection on its own, e.g. for health checks.
+///
+/// See <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>.
+pub(crate) async fn read_header<S>(stream: &mut S) -> io::Result<Option<Addresses>>
+where
+    S: AsyncRead + Unpin,
+{
+    // the shortest possible header is "PROXY UNKNOWN\r\n", so this does not
+    // read past the header
+    let mut start = [0; 12];
+    stream.read_exact(&mut start).await?;
+
+    if &start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(b"PROXY ") {
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LENGTH {
+                return Err(invalid("header too long"));
+            }
+            line.push(stream.read_u8().await?);
+        }
+        line.truncate(line.len() - 2);
+        let line = std::str::from_utf8(&line).map_err(|_| invalid("header is not ASCII"))?;
+        parse_v1(line)
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "PROXY protocol header missing",
+        ))
+    }
+}
+
+/// Parses a version 1 header without the CRLF, e.g.
+/// `PROXY TCP4 192.0.2.1 198.51.100.1 56324 1965`.
+fn parse_v1(line: &str) -> io::Result<Option<Addresses>> {
+    let mut fields = line.split(' ').skip(1);
+    let protocol = fields.next().ok_or(invalid("protocol missing"))?;
+    if protocol == "UNKNOWN" {
+        // the rest of the line has to be ignored
+        return Ok(None);
+    }
+
+    let mut next = || fields.next().ok_or(invalid("address missing"));
+    let (source, destination, source_port, destination_port) = (next()?, next()?, next()?, next()?);
+    if fields.next().is_some() {
+        return Err(invalid("too many fields"));
+    }
+
+    let ip = |s: &str| -> io::Result<IpAddr> {
+        match protocol {
+            "TCP4" => s.parse::<Ipv4Addr>().map(IpAddr::V4),
+            "TCP6" => s.parse::<Ipv6Addr>().map(IpAddr::V6),
+            _ => return Err(invalid("unknown protocol")),
+        }
+        .map_err(|_| invalid("invalid address"))
+    };
+    let port = |s: &str| s.parse::<u16>().map_err(|_| invalid("invalid port"));
+    Ok(Some(Addresses {
+        source: SocketAddr::new(ip(source)?, port(source_port)?),
+        destination: SocketAddr::new(ip(destination)?, port(destination_port)?),
+    }))
+}
+
+/// Reads the rest of a version 2 header after the signature.
+async fn read_v2<S>(stream: &mut S) -> io::Result<Option<Addresses>>
+where
+    S: AsyncRead + Unpin,
+{
+    let version_command = stream.read_u8().await?;
+    let family = stream.read_u8().await?;
+    let len = stream.read_u16().await?;
+    let mut data = vec![0; len.into()];
+    stream.read_exact(&mut data).await?;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported version"));
+    }
+    match version_command & 0xf {
+        // LOCAL, the connection was made by the proxy itself
+        0 => return Ok(None),
+        // PROXY
+        1 => (),
+        _ => return Err(invalid("unknown command")),
+    }
+
+    // the addresses may be followed by TLVs, which are ignored
+    let port = |data: &[u8]| u16::from_be_bytes([data[0], data[1]]);
+    match family {
+        // TCP over IPv4
+        0x11 if data.len() >= 12 => {
+            let ip = |data: &[u8]| IpAddr::from(<[u8; 4]>::try_from(data).unwrap());
+            Ok(Some(Addresses {
+                source: SocketAddr::new(ip(&data[0..4]), port(&data[8..10])),
+                destination: SocketAddr::new(ip(&data[4..8]), port(&data[10..12])),
+            }))
+        }
+        // TCP over IPv6
+        0x21 if data.len() >= 36 => {
+            let ip = |data: &[u8]| IpAddr::from(<[u8; 16]>::try_from(data).unwrap());
+            Ok(Some(Addresses {
+                source: SocketAddr::new(ip(&data[0..16]), port(&data[32..34])),
+                destination: SocketAddr::new(ip(&data[16..32]), port(&data[34..36])),
+            }))
+        }
+        0x11 | 0x21 => Err(invalid("addresses too short")),
+        // unspecified, UDP or Unix sockets, which do not have IP addresses
+        _ => Ok(None),
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid PROXY protocol header: {msg}"),
+    )
+}
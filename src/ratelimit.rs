@@ -0,0 +1,206 @@
+use {
+    std::{
+        collections::HashMap,
+        net::{IpAddr, SocketAddr},
+        str::FromStr,
+        sync::{Arc, Mutex},
+        time::Duration,
+    },
+    tokio::{
+        sync::{OwnedSemaphorePermit, Semaphore},
+        time::Instant,
+    },
+};
+
+/// How often buckets that are full again are removed, so the memory used does
+/// not grow with every client that ever connected.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A rate limit of a number of requests in a number of seconds. Clients may
+/// send that many requests at once, after that they have to wait.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RateLimit {
+    requests: u32,
+    seconds: u32,
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    /// Parses a rate limit from the format `REQUESTS/SECONDS`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("rate limit {s:?} is not of the form REQUESTS/SECONDS");
+        let (requests, seconds) = s.split_once('/').ok_or_else(err)?;
+        let limit = Self {
+            requests: requests.trim().parse().map_err(|_| err())?,
+            seconds: seconds.trim().parse().map_err(|_| err())?,
+        };
+        if limit.requests == 0 || limit.seconds == 0 {
+            return Err(format!("rate limit {s:?} must not be zero"));
+        }
+        Ok(limit)
+    }
+}
+
+impl RateLimit {
+    /// The number of requests that become available again per second.
+    fn rate(&self) -> f64 {
+        f64::from(self.requests) / f64::from(self.seconds)
+    }
+}
+
+/// Identifies a client for rate limiting. IPv6 clients are limited by /64
+/// prefix, since a single client can usually use any address in it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Client {
+    V4(u32),
+    V6(u64),
+}
+
+impl From<IpAddr> for Client {
+    fn from(addr: IpAddr) -> Self {
+        match addr.to_canonical() {
+            IpAddr::V4(addr) => Self::V4(addr.to_bits()),
+            IpAddr::V6(addr) => Self::V6((addr.to_bits() >> 64) as u64),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// The number of requests the client may still send.
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Adds the tokens that became available since the last update.
+    fn refill(&mut self, now: Instant, limit: &RateLimit) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate()).min(f64::from(limit.requests));
+        self.updated = now;
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    buckets: HashMap<Client, Bucket>,
+    last_cleanup: Instant,
+}
+
+/// Limits the rate of requests per client using a token bucket for each
+/// client.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    limit: RateLimit,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            buckets: Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                last_cleanup: Instant::now(),
+            }),
+        }
+    }
+
+    /// Counts a request from the address. If the client is over the limit,
+    /// returns the number of seconds it has to wait before sending another
+    /// request.
+    pub fn check(&self, addr: IpAddr) -> Result<(), u64> {
+        let now = Instant::now();
+        let limit = &self.limit;
+        let mut buckets = self.buckets.lock().expect("rate limiter poisoned");
+
+        if now.duration_since(buckets.last_cleanup) >= CLEANUP_INTERVAL {
+            buckets.buckets.retain(|_, bucket| {
+                bucket.refill(now, limit);
+                bucket.tokens < f64::from(limit.requests)
+            });
+            buckets.last_cleanup = now;
+        }
+
+        let bucket = buckets
+            .buckets
+            .entry(Client::from(addr))
+            .or_insert_with(|| Bucket {
+                tokens: f64::from(limit.requests),
+                updated: now,
+            });
+        bucket.refill(now, limit);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / limit.rate()).ceil() as u64)
+        }
+    }
+}
+
+/// The limits that apply to the connections of a listener.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Limits {
+    pub rate: Option<Arc<RateLimiter>>,
+    /// Limits the number of connections to this listener.
+    pub connections: Option<Arc<Semaphore>>,
+    /// Limits the number of connections to all listeners together.
+    pub global_connections: Option<Arc<Semaphore>>,
+}
+
+impl Limits {
+    /// Waits until another connection may be accepted. The returned permits
+    /// have to be kept while the connection is open. The permit for the
+    /// listener is acquired first, so a listener that is at its limit does
+    /// not hold on to global permits that other listeners could use.
+    pub async fn acquire(&self) -> Vec<OwnedSemaphorePermit> {
+        let mut permits = vec![];
+        for semaphore in [&self.connections, &self.global_connections]
+            .into_iter()
+            .flatten()
+        {
+            permits.push(
+                semaphore
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("connection limit closed"),
+            );
+        }
+        permits
+    }
+}
+
+/// Parses options of the form `[LISTENER=]VALUE`. If the listener is a
+/// socket address it is normalized, so it can be compared to the listening
+/// addresses.
+pub(crate) fn parse_per_listener<T>(specs: Vec<String>) -> Result<Vec<(Option<String>, T)>, String>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    specs
+        .into_iter()
+        .map(|spec| {
+            let (listener, value) = match spec.rsplit_once('=') {
+                Some((listener, value)) => (Some(normalize_listener(listener)), value),
+                None => (None, spec.as_str()),
+            };
+            let value = value
+                .parse()
+                .map_err(|e| format!("invalid value {value:?}: {e}"))?;
+            Ok((listener, value))
+        })
+        .collect()
+}
+
+/// Brings a listener name into the format `SocketAddr` is displayed in, if it
+/// is a socket address. Paths of Unix sockets are used as they are.
+pub(crate) fn normalize_listener(listener: &str) -> String {
+    listener
+        .parse::<SocketAddr>()
+        .map_or_else(|_| listener.to_string(), |addr| addr.to_string())
+}
@@ -0,0 +1,105 @@
+use {
+    std::{fmt::Write, str::FromStr},
+    tokio::io::{AsyncWrite, AsyncWriteExt},
+};
+
+#[cfg(unix)]
+use std::path::PathBuf;
+
+/// The address of an SCGI backend.
+#[derive(Debug)]
+pub(crate) enum Backend {
+    /// A TCP address, the host part may also be a domain name.
+    Tcp(String),
+    /// The path of a Unix socket.
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+/// Forwards requests for URL paths below a prefix to an SCGI backend.
+#[derive(Debug)]
+pub(crate) struct Route {
+    /// The normalized hostname the route is for, or `None` for all hosts.
+    pub host: Option<String>,
+    /// The URL path prefix, without a trailing slash.
+    pub prefix: String,
+    pub backend: Backend,
+}
+
+impl FromStr for Route {
+    type Err = String;
+
+    /// Parses a route from the format `[HOSTNAME]PREFIX=BACKEND`, where
+    /// `BACKEND` is either `HOST:PORT` or `unix:PATH`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (prefix, backend) = s
+            .split_once('=')
+            .ok_or_else(|| format!("SCGI route {s:?} is not of the form PREFIX=BACKEND"))?;
+
+        let (host, prefix) = match prefix.find('/') {
+            Some(0) => (None, prefix),
+            Some(i) => {
+                let host = url::Host::parse(&prefix[..i])
+                    .map_err(|e| format!("invalid hostname in SCGI route {s:?}: {e}"))?;
+                (Some(host.to_string()), &prefix[i..])
+            }
+            None => {
+                return Err(format!(
+                    "SCGI prefix {prefix:?} does not start with a slash"
+                ));
+            }
+        };
+
+        let backend = match backend.strip_prefix("unix:") {
+            #[cfg(unix)]
+            Some(path) => Backend::Unix(PathBuf::from(path)),
+            #[cfg(not(unix))]
+            Some(_) => return Err("Unix sockets are not supported on this platform".into()),
+            None if backend.contains(':') => Backend::Tcp(backend.to_string()),
+            None => return Err(format!("SCGI backend {backend:?} does not specify a port")),
+        };
+
+        Ok(Self {
+            host,
+            prefix: prefix.trim_end_matches('/').to_string(),
+            backend,
+        })
+    }
+}
+
+impl Route {
+    /// If the URL path on the normalized hostname is handled by this route,
+    /// returns the rest of the path after the prefix.
+    pub fn strip_prefix<'a>(&self, host: &str, path: &'a str) -> Option<&'a str> {
+        if self
+            .host
+            .as_ref()
+            .is_some_and(|route_host| route_host != host)
+        {
+            return None;
+        }
+        path.strip_prefix(&self.prefix)
+            .filter(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+}
+
+/// Sends the request headers to the backend. Since Gemini requests do not have
+/// a body, the request is complete after this.
+pub(crate) async fn send_request<S>(backend: &mut S, vars: &[(&str, String)]) -> std::io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    // CONTENT_LENGTH has to be the first header, SCGI is required too
+    let mut headers = String::from("CONTENT_LENGTH\x000\x00SCGI\x001\x00");
+    for (name, value) in vars {
+        // null bytes would corrupt the header list, they can only come from
+        // percent-decoding the path, which is not worth sending to a backend
+        let value = value.replace('\0', "");
+        write!(headers, "{name}\0{value}\0").unwrap();
+    }
+
+    // the headers are encoded as a netstring
+    let request = format!("{}:{headers},", headers.len());
+    backend.write_all(request.as_bytes()).await?;
+    backend.flush().await
+}
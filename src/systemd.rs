@@ -0,0 +1,102 @@
+use {
+    sd_notify::NotifyState,
+    std::{net::TcpListener, os::unix::net::UnixListener, time::Duration},
+};
+
+/// A listening socket that was passed in by the service manager.
+#[derive(Debug)]
+pub(crate) enum Socket {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+#[derive(Debug)]
+pub(crate) struct Listener {
+    /// The address or path the socket listens on, which is the name of the
+    /// listener in options.
+    pub name: String,
+    /// The name from `LISTEN_FDNAMES`, i.e. `FileDescriptorName=` of the
+    /// socket unit.
+    pub fd_name: Option<String>,
+    pub socket: Socket,
+}
+
+/// Takes the listening sockets passed in through `LISTEN_FDS`, as done by
+/// systemd socket activation.
+pub(crate) fn listen_fds() -> Result<Vec<Listener>, String> {
+    let fd_names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+    let fd_names = fd_names.split(':').collect::<Vec<_>>();
+
+    let mut fds = listenfd::ListenFd::from_env();
+    (0..fds.len())
+        .map(|i| {
+            let fd_name = fd_names
+                .get(i)
+                .filter(|name| !name.is_empty())
+                .map(|name| name.to_string());
+
+            let (name, socket) = if let Ok(Some(listener)) = fds.take_tcp_listener(i) {
+                let addr = listener
+                    .local_addr()
+                    .map_err(|e| format!("invalid passed socket {i}: {e}"))?;
+                (addr.to_string(), Socket::Tcp(listener))
+            } else {
+                let listener = fds
+                    .take_unix_listener(i)
+                    .map_err(|e| {
+                        format!("passed socket {i} is neither a TCP nor a Unix socket: {e}")
+                    })?
+                    .expect("socket was not taken before");
+                let addr = listener
+                    .local_addr()
+                    .map_err(|e| format!("invalid passed socket {i}: {e}"))?;
+                let name = match addr.as_pathname() {
+                    Some(path) => path.display().to_string(),
+                    // unnamed or abstract sockets
+                    None => format!("{addr:?}"),
+                };
+                (name, Socket::Unix(listener))
+            };
+
+            Ok(Listener {
+                name,
+                fd_name,
+                socket,
+            })
+        })
+        .collect()
+}
+
+/// Tells the service manager that agate has started.
+pub(crate) fn notify_ready() {
+    notify(&[NotifyState::Ready, NotifyState::Status("Listening")]);
+}
+
+/// Tells the service manager that agate is shutting down.
+pub(crate) fn notify_stopping() {
+    notify(&[
+        NotifyState::Stopping,
+        NotifyState::Status("Waiting for connections to finish"),
+    ]);
+}
+
+fn notify(state: &[NotifyState]) {
+    if let Err(e) = sd_notify::notify(false, state) {
+        log::warn!("Could not notify service manager: {e}");
+    }
+}
+
+/// Keeps telling the service manager that agate is alive, if it asked for
+/// that with `WatchdogSec=`.
+pub(crate) async fn watchdog() {
+    let mut usec = 0;
+    if !sd_notify::watchdog_enabled(false, &mut usec) {
+        return;
+    }
+    // ping twice per timeout, as recommended by sd_watchdog_enabled(3)
+    let mut interval = tokio::time::interval(Duration::from_micros(usec) / 2);
+    loop {
+        interval.tick().await;
+        notify(&[NotifyState::Watchdog]);
+    }
+}
@@ -0,0 +1,142 @@
+use {
+    percent_encoding::percent_decode_str,
+    std::{
+        io,
+        path::{Path, PathBuf},
+        sync::atomic::{AtomicU64, Ordering},
+        time::Duration,
+    },
+    tokio::{
+        fs::{self, File},
+        io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+        time::timeout,
+    },
+    url::Url,
+};
+
+/// The parameters of a Titan upload, which are appended to the path of the
+/// URL, e.g. `titan://example.com/notes/a.gmi;mime=text/gemini;size=12`.
+#[derive(Debug)]
+pub(crate) struct Upload {
+    /// The number of bytes that follow the request line.
+    pub size: u64,
+    pub token: Option<String>,
+}
+
+impl Upload {
+    /// Parses the parameters of the upload and removes them from the URL, so
+    /// only the path of the uploaded resource remains.
+    pub fn from_url(url: &mut Url) -> Result<Self, &'static str> {
+        let full_path = url.path().to_string();
+        let (path, params) = full_path
+            .split_once(';')
+            .ok_or("Upload parameters missing")?;
+
+        let mut size = None;
+        let mut token = None;
+        for param in params.split(';') {
+            let (key, value) = param.split_once('=').ok_or("Invalid upload parameter")?;
+            let value = percent_decode_str(value)
+                .decode_utf8()
+                .or(Err("Invalid upload parameter"))?
+                .into_owned();
+            match key {
+                "size" => size = Some(value.parse().or(Err("Invalid upload size"))?),
+                "token" => token = Some(value),
+                // the MIME type of uploaded files is determined like for any
+                // other file, so the mime parameter is not used
+                _ => (),
+            }
+        }
+
+        url.set_path(path);
+        Ok(Self {
+            size: size.ok_or("Upload size missing")?,
+            token,
+        })
+    }
+}
+
+/// Removes the value of the token parameter from a Titan request, so it does
+/// not end up in the log.
+pub(crate) fn redact_token(request: &str) -> String {
+    request
+        .split(';')
+        .map(|param| {
+            if param.starts_with("token=") {
+                "token=[redacted]"
+            } else {
+                param
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+/// Stores `size` bytes of the body at the path. The data is written to a
+/// temporary file next to it first, which is then renamed, so that a partial
+/// upload never replaces the file. Fails if the body does not deliver any data
+/// for `idle_timeout`.
+pub(crate) async fn store<R>(
+    path: &Path,
+    mut body: R,
+    size: u64,
+    idle_timeout: Duration,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let temp_path = temp_path(path);
+
+    let result = async {
+        let mut file = File::create(&temp_path).await?;
+        let mut buf = vec![0; 16 * 1024];
+        let mut remaining = size;
+        while remaining > 0 {
+            let len = buf
+                .len()
+                .min(usize::try_from(remaining).unwrap_or(usize::MAX));
+            let read = timeout(idle_timeout, body.read(&mut buf[..len]))
+                .await
+                .map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "client did not send the upload in time",
+                    )
+                })??;
+            if read == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "upload ended unexpectedly",
+                ));
+            }
+            file.write_all(&buf[..read]).await?;
+            remaining -= read as u64;
+        }
+        file.sync_all().await
+    }
+    .await;
+
+    match result {
+        Ok(()) => fs::rename(&temp_path, path).await,
+        Err(e) => {
+            let _ = fs::remove_file(&temp_path).await;
+            Err(e)
+        }
+    }
+}
+
+/// Returns a unique path for a hidden temporary file in the same directory as
+/// the path, so it can be renamed to the path atomically.
+fn temp_path(path: &Path) -> PathBuf {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(format!(
+        ".{}-{}.upload",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    path.with_file_name(name)
+}
@@ -0,0 +1,185 @@
+use {
+    crate::{config::DirectoryListing, metadata::PresetMeta},
+    std::{
+        collections::HashMap,
+        path::{Path, PathBuf},
+        sync::Arc,
+    },
+    url::Host,
+};
+
+/// The content and settings for a hostname.
+#[derive(Debug)]
+pub(crate) struct VirtualHost {
+    /// The content directory.
+    pub root: PathBuf,
+    /// The directory of the central `.meta` file, and the last directory whose
+    /// `.meta` file applies otherwise. This is the content directory, unless
+    /// the host uses a directory with its name in the content directory,
+    /// which then shares the `.meta` file of the content directory.
+    pub config_root: PathBuf,
+    /// The default language for text/gemini documents.
+    pub language: Option<String>,
+    /// Whether files and directories starting with a dot are served.
+    pub serve_secret: bool,
+    /// Whether only the `.meta` file in `config_root` is used.
+    pub central_config: bool,
+    pub directory_listing: DirectoryListing,
+}
+
+impl VirtualHost {
+    /// The preset for files without an entry in a `.meta` file.
+    pub fn default_preset(&self) -> PresetMeta {
+        PresetMeta::Parameters(
+            self.language
+                .as_ref()
+                .map_or(String::new(), |lang| format!(";lang={lang}")),
+        )
+    }
+}
+
+/// The virtual hosts by hostname.
+#[derive(Debug)]
+pub(crate) struct VirtualHosts {
+    /// The hosts by normalized hostname, aliases share the host.
+    hosts: HashMap<String, Arc<VirtualHost>>,
+    /// The host for requests that are not for one of the hostnames, i.e. if
+    /// the hostname check is disabled or the URL contains an IP address.
+    default: Arc<VirtualHost>,
+    /// Whether there are several hosts with their own directory in the
+    /// content directory, which is then also used for the default host.
+    separate_dirs: bool,
+}
+
+impl VirtualHosts {
+    /// Sets up a host for each hostname. Hosts without a content directory use
+    /// the default content directory, or a directory with their name in it if
+    /// there are several hosts. Other settings are taken from the `default`
+    /// host if not configured. Hosts can only share a content directory as
+    /// aliases.
+    pub fn new(
+        hostnames: &[Host],
+        mut configs: HashMap<String, crate::config::HostConfig>,
+        default: VirtualHost,
+    ) -> Result<Self, String> {
+        // find out which hostnames are aliases for other hostnames
+        let mut alias_of = HashMap::new();
+        for (name, config) in &configs {
+            for alias in &config.aliases {
+                let alias = Host::parse(alias)
+                    .map_err(|e| format!("invalid alias {alias:?} of {name}: {e}"))?
+                    .to_string();
+                if configs.contains_key(&alias) {
+                    return Err(format!(
+                        "{alias} is an alias of {name}, but also has its own settings"
+                    ));
+                }
+                if let Some(other) = alias_of.insert(alias.clone(), name.clone()) {
+                    return Err(format!("{alias} is an alias of both {other} and {name}"));
+                }
+            }
+        }
+
+        let separate_dirs = hostnames
+            .iter()
+            .filter(|hostname| !alias_of.contains_key(&hostname.to_string()))
+            .count()
+            > 1;
+
+        let mut hosts = HashMap::new();
+        // the host of a path is found by its content directory
+        let mut root_of = HashMap::new();
+        for hostname in hostnames {
+            let name = hostname.to_string();
+            if alias_of.contains_key(&name) {
+                continue;
+            }
+            let config = configs.remove(&name).unwrap_or_default();
+            let (root, config_root) = match config.content_dir {
+                Some(root) => (root.clone(), root),
+                None if separate_dirs => (default.root.join(&name), default.root.clone()),
+                None => (default.root.clone(), default.root.clone()),
+            };
+            if let Some(other) = root_of.insert(root.clone(), name.clone()) {
+                return Err(format!(
+                    "{other} and {name} have the same content directory {root:?}, \
+                    use `aliases` to share it"
+                ));
+            }
+            let host = VirtualHost {
+                root,
+                config_root,
+                language: config.language.or_else(|| default.language.clone()),
+                serve_secret: config.serve_secret.unwrap_or(default.serve_secret),
+                central_config: config.central_config.unwrap_or(default.central_config),
+                directory_listing: config.directory_listing,
+            };
+            hosts.insert(name, Arc::new(host));
+        }
+        for (alias, name) in alias_of {
+            let host = hosts[&name].clone();
+            hosts.insert(alias, host);
+        }
+
+        Ok(Self {
+            hosts,
+            default: Arc::new(default),
+            separate_dirs,
+        })
+    }
+
+    /// Checks if requests for the domain should be answered. If there are no
+    /// hostnames, the hostname check is disabled.
+    pub fn accepts(&self, domain: &str) -> bool {
+        self.hosts.is_empty() || self.hosts.contains_key(domain)
+    }
+
+    /// Returns the host for the normalized hostname from a request.
+    pub fn get(&self, hostname: &str) -> &VirtualHost {
+        self.hosts.get(hostname).unwrap_or(&self.default)
+    }
+
+    /// Returns the content directory for the normalized hostname from a
+    /// request.
+    pub fn root(&self, hostname: &str) -> PathBuf {
+        match self.hosts.get(hostname) {
+            Some(host) => host.root.clone(),
+            // keep other hosts out of each other's directories
+            None if self.separate_dirs => self.default.root.join(hostname),
+            None => self.default.root.clone(),
+        }
+    }
+
+    /// Returns the content directories of all hosts, without the ones that
+    /// are inside of another one.
+    pub fn roots(&self) -> Vec<&Path> {
+        let mut roots: Vec<&Path> = self
+            .hosts
+            .values()
+            .chain([&self.default])
+            .map(|host| host.config_root.as_path())
+            .collect();
+        roots.sort();
+        roots.dedup();
+        roots
+            .iter()
+            .filter(|root| {
+                !roots
+                    .iter()
+                    .any(|other| other != *root && root.starts_with(other))
+            })
+            .copied()
+            .collect()
+    }
+
+    /// Returns the host whose content directory contains the path. If the
+    /// content directories are nested, the innermost one is used.
+    pub fn for_path(&self, path: &Path) -> &VirtualHost {
+        self.hosts
+            .values()
+            .map(Arc::as_ref)
+            .filter(|host| path.starts_with(&host.root))
+            .max_by_key(|host| host.root.components().count())
+            .unwrap_or(&self.default)
+    }
+}
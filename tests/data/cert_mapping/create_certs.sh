@@ -0,0 +1,23 @@
+#!/bin/bash
+
+# create a certificate for example.com and its subdomains
+cat >openssl.conf <<EOT
+[req]
+distinguished_name = req_distinguished_name
+prompt = no
+x509_extensions = v3_ca
+
+[req_distinguished_name]
+commonName = example.com
+
+[v3_ca]
+# the generated certificate must not be CA-capable, otherwise rustls complains
+basicConstraints = critical, CA:false
+subjectAltName = DNS:example.com, DNS:*.example.com
+EOT
+
+openssl req -x509 -new -newkey ec -pkeyopt ec_paramgen_curve:P-256 -nodes \
+	-keyout wildcard.key.pem -out wildcard.cert.pem -days 3650 -config openssl.conf
+
+# clean up
+rm openssl.conf
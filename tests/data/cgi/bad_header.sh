@@ -0,0 +1,2 @@
+#!/bin/sh
+echo "This is not a valid response header."
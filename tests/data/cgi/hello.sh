@@ -0,0 +1,5 @@
+#!/bin/sh
+printf '20 text/plain\r\n'
+printf 'query:%s\n' "$QUERY_STRING"
+printf 'path:%s\n' "$PATH_INFO"
+printf 'script:%s\n' "$SCRIPT_NAME"
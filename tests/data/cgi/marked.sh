@@ -0,0 +1,2 @@
+#!/bin/sh
+printf '20 text/gemini\r\nmarked as CGI\n'
@@ -0,0 +1,3 @@
+#!/bin/sh
+sleep 5
+printf '20 text/plain\r\n'
@@ -0,0 +1,19 @@
+#!/bin/bash
+
+# client certificates are self signed, like most Gemini clients would do it
+for name in "alice" "bob" "expired"
+do
+mkdir -p $name
+
+# create private key
+openssl genpkey -out $name/key.pem -algorithm EC -pkeyopt ec_paramgen_curve:P-256
+
+if [ "$name" = "expired" ]
+then
+	validity="-not_before 20200101000000Z -not_after 20210101000000Z"
+else
+	validity="-days 36500"
+fi
+
+openssl req -new -x509 -sha256 -key $name/key.pem -out $name/cert.pem -subj "/CN=$name" $validity
+done
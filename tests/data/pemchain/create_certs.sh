@@ -0,0 +1,49 @@
+#!/bin/bash
+
+# creates a certificate for example.com that is signed by an intermediate CA,
+# which in turn is signed by a root CA, like certificates issued by a real CA
+
+mkdir -p example.com
+
+cat >openssl.conf <<EOT
+[req]
+distinguished_name = req_distinguished_name
+prompt = no
+
+[req_distinguished_name]
+commonName = Agate Test CA
+
+[ca_ext]
+basicConstraints = critical, CA:true
+keyUsage = critical, keyCertSign, cRLSign
+
+[leaf_ext]
+# the generated certificate must not be CA-capable, otherwise rustls complains
+basicConstraints = critical, CA:false
+subjectAltName = DNS:example.com
+EOT
+
+# root CA, only the certificate is needed by the tests
+openssl req -x509 -new -newkey ec -pkeyopt ec_paramgen_curve:P-256 -nodes \
+	-keyout root.key -out root.pem -days 3650 -config openssl.conf \
+	-subj "/CN=Agate Test Root CA" -extensions ca_ext
+
+# intermediate CA
+openssl req -new -newkey ec -pkeyopt ec_paramgen_curve:P-256 -nodes \
+	-keyout intermediate.key -out intermediate.csr -config openssl.conf \
+	-subj "/CN=Agate Test Intermediate CA"
+openssl x509 -req -in intermediate.csr -CA root.pem -CAkey root.key \
+	-CAcreateserial -out intermediate.pem -days 3650 \
+	-extfile openssl.conf -extensions ca_ext
+
+# certificate for example.com
+openssl req -new -newkey ec -pkeyopt ec_paramgen_curve:P-256 -nodes \
+	-keyout example.com/key.pem -out example.com.csr -config openssl.conf \
+	-subj "/CN=example.com"
+openssl x509 -req -in example.com.csr -CA intermediate.pem -CAkey intermediate.key \
+	-CAcreateserial -out example.com/cert.pem -days 3650 \
+	-extfile openssl.conf -extensions leaf_ext
+cat example.com/cert.pem intermediate.pem >example.com/fullchain.pem
+
+# clean up
+rm example.com/cert.pem openssl.conf *.csr *.key *.srl intermediate.pem
@@ -0,0 +1,6 @@
+#!/bin/bash
+
+# an expired self signed certificate like agate would have generated it,
+# reusing the expired client certificate
+openssl x509 -in ../client_certs/expired/cert.pem -outform DER -out example.com/cert.der
+openssl pkcs8 -topk8 -nocrypt -in ../client_certs/expired/key.pem -outform DER -out example.com/key.der
@@ -43,27 +43,40 @@ struct Server {
 
 impl Server {
     pub fn new(args: &[&str]) -> Self {
-        use std::net::{IpAddr, Ipv4Addr};
+        Self::new_with(args, |_, _| ())
+    }
 
-        let port = PORT.fetch_add(1, Ordering::SeqCst);
+    /// Starts the server after letting `setup` change the command, e.g. to
+    /// pass sockets or environment variables. `setup` also gets the address
+    /// the server will listen on.
+    pub fn new_with(args: &[&str], setup: impl FnOnce(&mut Command, SocketAddr)) -> Self {
+        use std::net::{IpAddr, Ipv4Addr};
 
-        // generate unique port/address so tests do not clash
-        let addr = (IpAddr::V4(Ipv4Addr::LOCALHOST), port)
-            .to_socket_addrs()
-            .unwrap()
-            .next()
-            .unwrap();
+        // generate unique port/address so tests do not clash, skipping ports
+        // that are used by other programs
+        let (port, addr) = loop {
+            let port = PORT.fetch_add(1, Ordering::SeqCst);
+            let addr = (IpAddr::V4(Ipv4Addr::LOCALHOST), port)
+                .to_socket_addrs()
+                .unwrap()
+                .next()
+                .unwrap();
+            if std::net::TcpListener::bind(addr).is_ok() {
+                break (port, addr);
+            }
+        };
 
         // start the server
-        let mut server = Command::new(BINARY_PATH)
+        let mut command = Command::new(BINARY_PATH);
+        command
             .stderr(Stdio::piped())
             .current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data"))
             // add address information
             .args(["--addr", &addr.to_string()])
             .args(args)
-            .env("RUST_LOG", "debug")
-            .spawn()
-            .expect("failed to start binary");
+            .env("RUST_LOG", "debug");
+        setup(&mut command, addr);
+        let mut server = command.spawn().expect("failed to start binary");
 
         // We can be sure that agate is listening because it logs a message saying so.
         let mut reader = BufReader::new(server.stderr.as_mut().unwrap());
@@ -93,6 +106,34 @@ impl Server {
         self.addr
     }
 
+    /// Sends SIGTERM to the server.
+    #[cfg(unix)]
+    pub fn terminate(&self) {
+        let status = Command::new("kill")
+            .args(["-TERM", &self.server.id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    /// Waits for the server to exit on its own and returns the rest of its
+    /// log.
+    pub fn wait(&mut self) -> String {
+        let status = self.server.wait().unwrap();
+        let mut log = String::new();
+        self.server
+            .stderr
+            .take()
+            .unwrap()
+            .read_to_string(&mut log)
+            .unwrap();
+        print!("{log}");
+        assert!(status.success(), "server exited with {status}");
+        // the server is already stopped
+        self.output = Some(Ok(()));
+        log
+    }
+
     pub fn stop(&mut self) -> Result<(), String> {
         // try to stop the server
         if let Some(output) = self.output.as_ref() {
@@ -145,24 +186,147 @@ impl Drop for Server {
     }
 }
 
+/// Sets up a TLS client for a server that uses the multicert certificates.
+fn client_session() -> ClientConnection {
+    let mut certs = RootCertStore::empty();
+    certs
+        .add(CertificateDer::from(
+            include_bytes!("data/multicert/example.com/cert.der").as_slice(),
+        ))
+        .unwrap();
+    let config = rustls::ClientConfig::builder()
+        .with_root_certificates(certs)
+        .with_no_client_auth();
+    ClientConnection::new(
+        std::sync::Arc::new(config),
+        "example.com".try_into().unwrap(),
+    )
+    .unwrap()
+}
+
+/// Sets up a TLS connection to the server, which has to use the multicert
+/// certificates.
+fn connect(server: &Server) -> (ClientConnection, TcpStream) {
+    let mut session = client_session();
+    let mut tcp = TcpStream::connect(server.get_addr()).unwrap();
+    tcp.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    while session.is_handshaking() {
+        session.complete_io(&mut tcp).unwrap();
+    }
+    (session, tcp)
+}
+
 fn get(args: &[&str], url: &str) -> Result<Response, String> {
+    request(args, url, Actor::default())
+}
+
+/// Like `get`, but using one of the client certificates from the
+/// `client_certs` directory.
+fn get_with_cert(args: &[&str], url: &str, cert: &str) -> Result<Response, String> {
+    let dir = PathBuf::from(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/client_certs"
+    ));
+    let actor = || {
+        Actor::default()
+            .cert_file(dir.join(cert).join("cert.pem"))
+            .key_file(dir.join(cert).join("key.pem"))
+    };
+
+    let mut server = Server::new(args);
+    let mut response = send(&server, url, actor());
+    // clients are only asked for certificates after the configuration file
+    // that requires them was read, so send the request again like a client
+    if response
+        .as_ref()
+        .is_ok_and(|response| response.status == Status::ClientCertificateRequired.value())
+    {
+        response = send(&server, url, actor());
+    }
+    server.stop()?;
+    response
+}
+
+fn request(args: &[&str], url: &str, actor: Actor) -> Result<Response, String> {
     let mut server = Server::new(args);
+    let response = send(&server, url, actor);
+    server.stop()?;
+    response
+}
 
+/// Sends a request to a running server.
+fn send(server: &Server, url: &str, actor: Actor) -> Result<Response, String> {
     let mut url = Url::parse(url).unwrap();
     if url.port().is_none() {
         // inject port number into URL because that is only determined
-        // when constructing the `Server` above.
+        // when the `Server` is started.
         url.set_port(Some(server.port)).unwrap();
     }
-    let actor = Actor::default().proxy("localhost".into(), server.addr.port());
+    let actor = actor.proxy("localhost".into(), server.addr.port());
     let request = actor.get(url);
 
-    let response = tokio::runtime::Runtime::new()
+    tokio::runtime::Runtime::new()
         .unwrap()
         .block_on(request)
-        .map_err(|e| e.to_string());
-    server.stop()?;
-    response
+        .map_err(|e| e.to_string())
+}
+
+/// A server for a content directory in the temporary directory, for tests
+/// that change files while the server is running. The directory is removed
+/// when this is dropped.
+struct TempServer {
+    content: PathBuf,
+    server: Server,
+}
+
+impl TempServer {
+    /// Creates a content directory called `name` with the `files`, given as
+    /// paths and contents, and starts a server for it with the extra `args`.
+    fn new(name: &str, files: &[(&str, &str)], args: &[&str]) -> Self {
+        let content =
+            std::env::temp_dir().join(format!("agate-test-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&content);
+        std::fs::create_dir_all(&content).unwrap();
+        for (path, contents) in files {
+            write_file(&content.join(path), contents);
+        }
+        let server = Server::new(&[&["--content", content.to_str().unwrap()], args].concat());
+        Self { content, server }
+    }
+
+    /// Returns the path of a file in the content directory.
+    fn path(&self, path: &str) -> PathBuf {
+        self.content.join(path)
+    }
+
+    /// Writes a file in the content directory.
+    fn write(&self, path: &str, contents: &str) {
+        write_file(&self.path(path), contents);
+    }
+
+    /// Requests a path from the server.
+    fn get(&self, path: &str) -> Response {
+        let actor = Actor::default().proxy("localhost".into(), self.server.port);
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(actor.get(format!("gemini://localhost:{}{path}", self.server.port)))
+            .expect("could not get page")
+    }
+}
+
+impl Drop for TempServer {
+    fn drop(&mut self) {
+        if !std::thread::panicking() {
+            self.server.stop().unwrap();
+        }
+        let _ = std::fs::remove_dir_all(&self.content);
+    }
+}
+
+/// Writes a file, creating its parent directories.
+fn write_file(path: &std::path::Path, contents: &str) {
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(path, contents).unwrap();
 }
 
 #[test]
@@ -291,6 +455,274 @@ fn full_header_preset() {
     assert_eq!(page.meta, "This file is no longer available.");
 }
 
+#[test]
+/// - changes to configuration files are noticed without their modification time
+/// - renamed directories are noticed
+fn meta_watch() {
+    let temp = TempServer::new(
+        "meta-watch",
+        &[
+            ("page.txt", "text"),
+            (".meta", "page.txt: text/x-aaa\n*.new: text/x-new\n"),
+        ],
+        &["--watch"],
+    );
+    // the watcher notices changes asynchronously
+    let eventually = |path: &str, expected: &str| {
+        for _ in 0..50 {
+            if temp.get(path).meta == expected {
+                return;
+            }
+            sleep(Duration::from_millis(100));
+        }
+        assert_eq!(temp.get(path).meta, expected);
+    };
+
+    assert_eq!(temp.get("/page.txt").meta, "text/x-aaa");
+    assert_eq!(temp.get("/file.new").meta, "Not found, sorry.");
+
+    // same size and modification time, so only the watcher notices
+    let modified = std::fs::metadata(temp.path(".meta"))
+        .unwrap()
+        .modified()
+        .unwrap();
+    temp.write(".meta", "page.txt: text/x-bbb\n*.new: text/x-new\n");
+    std::fs::File::options()
+        .write(true)
+        .open(temp.path(".meta"))
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+    eventually("/page.txt", "text/x-bbb");
+
+    temp.write("file.new", "new");
+    eventually("/file.new", "text/x-new");
+
+    // renaming a directory moves its configuration file along
+    temp.write("a/p.txt", "text");
+    temp.write("a/.meta", "p.txt: text/x-a\n");
+    std::fs::create_dir(temp.path("b")).unwrap();
+    assert_eq!(temp.get("/a/p.txt").meta, "text/x-a");
+    assert_eq!(temp.get("/b/p.txt").meta, "Not found, sorry.");
+    std::fs::rename(temp.path("a"), temp.path("b")).unwrap();
+    eventually("/b/p.txt", "text/x-a");
+    temp.write("a/p.txt", "text");
+    eventually("/a/p.txt", "text/plain");
+}
+
+#[test]
+/// - globs match files that were created after the configuration was read
+/// - entries without wildcards take precedence, then the longest pattern
+fn meta_glob_precedence() {
+    let temp = TempServer::new(
+        "meta-globs",
+        &[
+            ("page-1.gmi", "# Page\n"),
+            ("other.gmi", "# Page\n"),
+            (
+                ".meta",
+                "page-*.gmi: ;lang=de\n*.gmi: ;lang=en\npage-1.gmi: ;lang=fr\n",
+            ),
+        ],
+        &[],
+    );
+
+    assert_eq!(temp.get("/page-1.gmi").meta, "text/gemini;lang=fr");
+    assert_eq!(temp.get("/other.gmi").meta, "text/gemini;lang=en");
+
+    temp.write("page-2.gmi", "# Page\n");
+    assert_eq!(temp.get("/page-2.gmi").meta, "text/gemini;lang=de");
+}
+
+#[test]
+/// - redirect rules apply to paths that do not exist
+/// - glob captures are substituted in the target
+/// - entries without wildcards take precedence
+fn redirect_rules() {
+    let page = get(&[], "gemini://localhost/old/a/b.gmi").expect("could not get page");
+    assert_eq!(page.status, Status::RedirectPermanent.value());
+    assert!(page.meta.ends_with("/new/a/b.gmi"), "{}", page.meta);
+
+    let page = get(&[], "gemini://localhost/old/kept.gmi").expect("could not get page");
+    assert_eq!(page.status, Status::RedirectTemporary.value());
+    assert!(page.meta.ends_with("/kept.gmi"), "{}", page.meta);
+}
+
+#[test]
+/// - redirect rules can be regular expressions
+/// - redirect targets can be on other hosts
+fn redirect_regex() {
+    let page = get(&[], "gemini://localhost/moved-42.gmi").expect("could not get page");
+    assert_eq!(page.status, Status::RedirectPermanent.value());
+    assert_eq!(page.meta, "gemini://example.org/42.gmi");
+}
+
+#[test]
+/// - redirect sources that are cut at `:` or `=` are rejected
+/// - regular expressions can match `=` with an escape
+fn redirect_separators() {
+    let mut temp = TempServer::new("redirect-separators", &[], &[]);
+    // written after the start so the error is in the log that is checked
+    temp.write(
+        ".meta",
+        "[redirects]\n~a(b=c)x: /cut\n~page\\x3D(\\d+): /pages/$1\n",
+    );
+
+    let page = temp.get("/page=42");
+    assert_eq!(page.status, Status::RedirectPermanent.value());
+    assert!(page.meta.ends_with("/pages/42"), "{}", page.meta);
+
+    assert_eq!(temp.get("/ab=cx").status, Status::NotFound.value());
+
+    #[cfg(unix)]
+    {
+        temp.server.terminate();
+        let log = temp.server.wait();
+        assert!(
+            log.contains("invalid redirect for \"~a(b\""),
+            "no error in {log}"
+        );
+    }
+}
+
+#[test]
+/// - error messages can be set in configuration files
+/// - error messages are inherited by subdirectories
+/// - presets with only a status code use the error message
+/// - presets with only a success or redirect status code are ignored
+fn error_messages() {
+    let temp = TempServer::new(
+        "error-messages",
+        &[
+            ("ok.gmi", "# Page\n"),
+            ("moved.gmi", "# Page\n"),
+            (
+                ".meta",
+                "weg.gmi: 52\nok.gmi: 20\nmoved.gmi: 30\n\
+                [errors]\n51: Nicht gefunden.\n52: Weg.\nsecret: Geheim.\n",
+            ),
+            ("nl/.meta", "[errors]\n51: Niet gevonden.\n"),
+        ],
+        &[],
+    );
+    let get = |path: &str| {
+        let response = temp.get(path);
+        (response.status, response.meta)
+    };
+
+    assert_eq!(get("/missing.gmi"), (51, "Nicht gefunden.".into()));
+    assert_eq!(get("/nl/missing.gmi"), (51, "Niet gevonden.".into()));
+    assert_eq!(get("/nl/sub/missing.gmi"), (51, "Niet gevonden.".into()));
+    assert_eq!(get("/.hidden"), (52, "Geheim.".into()));
+    assert_eq!(get("/weg.gmi"), (52, "Weg.".into()));
+    assert_eq!(get("/ok.gmi"), (20, "text/gemini".into()));
+    assert_eq!(get("/moved.gmi"), (20, "text/gemini".into()));
+}
+
+#[test]
+/// - an incorrect line in a configuration file does not affect the others
+fn meta_incorrect_line() {
+    let names = ["a", "b", "c", "d", "e", "f"];
+    // the lines are not read in file order, so there are several others
+    let mut meta = "bad.txt: 2x Incorrect\n".to_string();
+    for name in names {
+        meta.push_str(&format!("{name}.txt: text/x-{name}\n"));
+    }
+    let temp = TempServer::new("meta-incorrect-line", &[(".meta", &meta)], &[]);
+
+    for name in names {
+        temp.write(&format!("{name}.txt"), "");
+        assert_eq!(
+            temp.get(&format!("/{name}.txt")).meta,
+            format!("text/x-{name}")
+        );
+    }
+}
+
+#[test]
+/// - configuration files apply to subdirectories through `**` globs and paths
+/// - the nearest configuration file with an entry for a file is used
+fn meta_inheritance() {
+    let temp = TempServer::new(
+        "meta-inheritance",
+        &[
+            ("sub/deep/a.gmi", "# Page\n"),
+            ("sub/b.gmi", "# Page\n"),
+            ("sub/c.txt", "# Page\n"),
+            (".meta", "**/*.gmi: ;lang=de\nsub/c.txt: text/x-c\n"),
+            ("sub/.meta", "b.gmi: ;lang=fr\n"),
+        ],
+        &[],
+    );
+
+    assert_eq!(temp.get("/sub/deep/a.gmi").meta, "text/gemini;lang=de");
+    assert_eq!(temp.get("/sub/b.gmi").meta, "text/gemini;lang=fr");
+    assert_eq!(temp.get("/sub/c.txt").meta, "text/x-c");
+}
+
+#[test]
+/// - a changed configuration file is read again
+fn meta_reload() {
+    let temp = TempServer::new(
+        "meta-reload",
+        &[("page.txt", "text"), (".meta", "page.txt: text/x-old\n")],
+        &[],
+    );
+
+    assert_eq!(temp.get("/page.txt").meta, "text/x-old");
+    // make sure the modification time changes
+    sleep(Duration::from_millis(50));
+    temp.write(".meta", "page.txt: text/x-new\n");
+    assert_eq!(temp.get("/page.txt").meta, "text/x-new");
+}
+
+#[test]
+/// - requests are answered while a configuration file is rewritten
+/// - every response uses one of the versions of the configuration file
+fn meta_concurrent_reload() {
+    use std::sync::atomic::AtomicBool;
+
+    let temp = TempServer::new(
+        "meta-concurrent",
+        &[("page.txt", "text"), (".meta", "page.txt: text/x-0\n")],
+        &[],
+    );
+    let done = AtomicBool::new(false);
+
+    std::thread::scope(|scope| {
+        let clients: Vec<_> = (0..4)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut requests = 0;
+                    while !done.load(Ordering::SeqCst) {
+                        let page = temp.get("/page.txt");
+                        assert_eq!(page.status, Status::Success.value());
+                        assert!(page.meta.starts_with("text/x-"), "{}", page.meta);
+                        requests += 1;
+                    }
+                    requests
+                })
+            })
+            .collect();
+
+        for i in 1..=20 {
+            // replace the file at once, so it is never read half written
+            temp.write(".meta.new", &format!("page.txt: text/x-{i}\n"));
+            std::fs::rename(temp.path(".meta.new"), temp.path(".meta")).unwrap();
+            sleep(Duration::from_millis(20));
+        }
+        done.store(true, Ordering::SeqCst);
+        for client in clients {
+            assert!(client.join().unwrap() > 0);
+        }
+    });
+
+    // the latest version is used afterwards
+    sleep(Duration::from_millis(50));
+    temp.write(".meta", "page.txt: text/x-done\n");
+    assert_eq!(temp.get("/page.txt").meta, "text/x-done");
+}
+
 #[test]
 /// - URLS with fragments are rejected
 fn fragment() {
@@ -541,6 +973,102 @@ mod vhosts {
             include_bytes!("data/content/example.org/index.gmi")
         );
     }
+
+    #[test]
+    /// - hostnames can have their own content directory and language
+    /// - aliases share the content directory and settings
+    fn alias() {
+        for host in ["example.com", "example.net"] {
+            let page = get(
+                &["--config", "vhosts/vhosts.ini"],
+                &format!("gemini://{host}/"),
+            )
+            .expect("could not get page");
+
+            assert_eq!(page.status, Status::Success.value());
+            assert_eq!(page.meta, "text/gemini;lang=fr");
+            assert_eq!(page.content, include_bytes!("data/vhosts/shared/index.gmi"));
+        }
+    }
+
+    #[test]
+    /// - other hosts still use the global settings
+    fn global_settings() {
+        let page = get(&["--config", "vhosts/vhosts.ini"], "gemini://example.org/")
+            .expect("could not get page");
+
+        assert_eq!(page.status, Status::Success.value());
+        assert_eq!(page.meta, "text/gemini;lang=en");
+        assert_eq!(
+            page.content,
+            include_bytes!("data/content/example.org/index.gmi")
+        );
+    }
+
+    #[test]
+    /// - hosts can have their own secret file policy
+    fn serve_secret() {
+        let page = get(
+            &["--config", "vhosts/vhosts.ini"],
+            "gemini://example.net/.secret.gmi",
+        )
+        .expect("could not get page");
+        assert_eq!(page.status, Status::Success.value());
+
+        let page = get(
+            &["--config", "vhosts/vhosts.ini"],
+            "gemini://example.org/.secret.gmi",
+        )
+        .expect("could not get page");
+        assert_eq!(page.status, Status::Gone.value());
+    }
+
+    #[test]
+    /// - hosts with directories in the content directory share its central
+    ///   .meta file
+    fn central_config_shared() {
+        let page = get(
+            &[
+                "-C",
+                "--hostname",
+                "example.com",
+                "--hostname",
+                "example.org",
+            ],
+            "gemini://example.com/index.gmi",
+        )
+        .expect("could not get page");
+
+        assert_eq!(page.status, Status::Success.value());
+        assert_eq!(page.meta, "text/gemini;lang=en-US");
+    }
+
+    #[test]
+    /// - the .meta file of the content directory applies to the directories
+    ///   of the hostnames
+    fn meta_inherited() {
+        let page = get(
+            &["--hostname", "example.com", "--hostname", "example.org"],
+            "gemini://example.com/index.gmi",
+        )
+        .expect("could not get page");
+
+        assert_eq!(page.status, Status::Success.value());
+        assert_eq!(page.meta, "text/gemini;lang=en-US");
+    }
+
+    #[test]
+    /// - hosts can use a central .meta file
+    fn central_config() {
+        let page = get(
+            &["--config", "vhosts/vhosts.ini"],
+            "gemini://example.com/sub/page.txt",
+        )
+        .expect("could not get page");
+
+        assert_eq!(page.status, Status::Success.value());
+        assert_eq!(page.meta, "text/x-page");
+    }
 }
 
 mod multicert {
@@ -567,6 +1095,57 @@ mod multicert {
         let _ = server.server.wait();
     }
 
+    #[test]
+    #[should_panic]
+    fn cert_ambiguous() {
+        let mut server = Server::new(&["--certs", "cert_ambiguous"]);
+
+        // wait for the server to stop, it should crash
+        let _ = server.server.wait();
+    }
+
+    #[test]
+    #[should_panic]
+    fn mixed_formats() {
+        let mut server = Server::new(&["--certs", "mixed_formats"]);
+
+        // wait for the server to stop, it should crash
+        let _ = server.server.wait();
+    }
+
+    #[test]
+    /// the server has to send the intermediate certificate from the PEM
+    /// chain, because the client only knows the root certificate
+    fn pem_chain() {
+        use rustls::pki_types::pem::PemObject;
+
+        let mut server = Server::new(&["--certs", "pemchain", "--hostname", "example.com"]);
+
+        let mut certs = RootCertStore::empty();
+        certs
+            .add(CertificateDer::from_pem_slice(include_bytes!("data/pemchain/root.pem")).unwrap())
+            .unwrap();
+        let config = rustls::ClientConfig::builder()
+            .with_root_certificates(certs)
+            .with_no_client_auth();
+
+        let mut session = ClientConnection::new(
+            std::sync::Arc::new(config),
+            "example.com".try_into().unwrap(),
+        )
+        .unwrap();
+        let mut tcp = TcpStream::connect(server.get_addr()).unwrap();
+        let mut tls = rustls::Stream::new(&mut session, &mut tcp);
+
+        write!(tls, "gemini://example.com:{}/\r\n", server.port).unwrap();
+
+        let mut buf = [0; 2];
+        tls.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"20");
+
+        server.stop().unwrap();
+    }
+
     #[test]
     fn example_com() {
         let mut server = Server::new(&["--certs", "multicert"]);
@@ -628,32 +1207,1739 @@ mod multicert {
     }
 }
 
-mod directory_listing {
+mod cert_mapping {
     use super::*;
+    use rustls::pki_types::pem::PemObject;
+
+    /// Does a TLS handshake with the server for the given server name,
+    /// trusting only the given certificate.
+    fn handshake(server: &Server, cert: CertificateDer<'static>, name: &str) -> bool {
+        let mut certs = RootCertStore::empty();
+        certs.add(cert).unwrap();
+        let config = rustls::ClientConfig::builder()
+            .with_root_certificates(certs)
+            .with_no_client_auth();
+
+        let mut session = ClientConnection::new(
+            std::sync::Arc::new(config),
+            name.to_string().try_into().unwrap(),
+        )
+        .unwrap();
+        let mut tcp = TcpStream::connect(server.get_addr()).unwrap();
+        while session.is_handshaking() {
+            if session.complete_io(&mut tcp).is_err() {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn wildcard_cert() -> CertificateDer<'static> {
+        CertificateDer::from_pem_slice(include_bytes!("data/cert_mapping/wildcard.cert.pem"))
+            .unwrap()
+    }
 
     #[test]
-    /// - shows directory listing when enabled
-    /// - shows directory listing preamble correctly
-    /// - encodes link URLs correctly
-    fn with_preamble() {
-        let page = get(&["--content", "dirlist-preamble"], "gemini://localhost/")
-            .expect("could not get page");
+    fn exact_name() {
+        let mut server = Server::new(&["--certs", "cert_mapping"]);
 
-        assert_eq!(page.status, Status::Success.value());
-        assert_eq!(page.meta, "text/gemini");
-        assert_eq!(
-            page.content,
-            b"This is a directory listing\n=> a\n=> b\n=> wao%20spaces wao spaces\n"
-        );
+        assert!(handshake(&server, wildcard_cert(), "example.com"));
+        assert!(handshake(
+            &server,
+            CertificateDer::from(include_bytes!("data/multicert/example.org/cert.der").as_slice()),
+            "example.org"
+        ));
+
+        server.stop().unwrap();
     }
 
     #[test]
-    fn empty_preamble() {
-        let page =
-            get(&["--content", "dirlist"], "gemini://localhost/").expect("could not get page");
+    fn wildcard() {
+        let mut server = Server::new(&["--certs", "cert_mapping"]);
 
-        assert_eq!(page.status, Status::Success.value());
-        assert_eq!(page.meta, "text/gemini");
-        assert_eq!(page.content, b"=> a\n=> b\n");
+        assert!(handshake(&server, wildcard_cert(), "www.example.com"));
+
+        server.stop().unwrap();
+    }
+
+    #[test]
+    /// names that are not in the mapping file do not get any certificate,
+    /// the wildcard also only matches a single label
+    fn unmapped_name() {
+        let mut server = Server::new(&["--certs", "cert_mapping"]);
+
+        assert!(!handshake(&server, wildcard_cert(), "a.b.example.com"));
+        assert!(!handshake(&server, wildcard_cert(), "example.net"));
+
+        server.stop().unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn name_not_covered() {
+        let mut server = Server::new(&["--certs", "cert_mapping_uncovered"]);
+
+        // wait for the server to stop, it should crash
+        let _ = server.server.wait();
+    }
+
+    #[test]
+    #[should_panic]
+    fn unmapped_hostname() {
+        let mut server = Server::new(&["--certs", "cert_mapping", "--hostname", "example.net"]);
+
+        // wait for the server to stop, it should crash
+        let _ = server.server.wait();
+    }
+
+    #[test]
+    /// the directory `ample.com` must not be used for `example.com`
+    fn directories_match_labels() {
+        let mut server = Server::new(&["--certs", "labelwise"]);
+
+        let cert =
+            CertificateDer::from(include_bytes!("data/multicert/example.com/cert.der").as_slice());
+        assert!(!handshake(&server, cert, "example.com"));
+
+        server.stop().unwrap();
+    }
+}
+
+mod check_certs {
+    use super::*;
+
+    /// Runs agate with `--check-certs`, returns whether it succeeded and the
+    /// printed report.
+    fn check_certs(args: &[&str]) -> (bool, String) {
+        let output = Command::new(BINARY_PATH)
+            .current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data"))
+            .arg("--check-certs")
+            .args(args)
+            .output()
+            .expect("failed to start binary");
+        (
+            output.status.success(),
+            String::from_utf8(output.stdout).unwrap(),
+        )
+    }
+
+    #[test]
+    fn valid() {
+        let (ok, report) = check_certs(&["--certs", "multicert", "--hostname", "example.com"]);
+        assert!(ok);
+        assert!(report.contains(
+            "fingerprint 8070867753c130bdd8e5b11f348ba4afa1b1bf2ff0723d3236876b319db78508"
+        ));
+        assert!(!report.contains("problem"));
+    }
+
+    #[test]
+    fn expired() {
+        let (ok, report) = check_certs(&["--certs", "expired_cert"]);
+        assert!(!ok);
+        assert!(report.contains("problem: the certificate expired on"));
+    }
+
+    #[test]
+    fn missing_hostname() {
+        let (ok, report) = check_certs(&["--certs", "multicert", "--hostname", "example.net"]);
+        assert!(!ok);
+        assert!(report.contains("problem: there is no certificate for example.net"));
+    }
+}
+
+mod cert_generation {
+    use super::*;
+    use std::fs;
+    use x509_parser::{extensions::GeneralName, prelude::*};
+
+    /// Creates an empty certificate directory in the temporary directory.
+    fn certs_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("agate-test-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn options() {
+        let certs = certs_dir("generate");
+
+        let mut server = Server::new(&[
+            "--certs",
+            certs.to_str().unwrap(),
+            "--hostname",
+            "example.com",
+            "--hostname",
+            "example.org",
+            "--cert-validity",
+            "30",
+            "--cert-san",
+            "www.example.com",
+            "--cert-san",
+            "example.org=192.0.2.1",
+            "--cert-subject",
+            "O=Agate,C=DE",
+        ]);
+        server.stop().unwrap();
+
+        let der = fs::read(certs.join("example.org/cert.der")).unwrap();
+        let (_, cert) = parse_x509_certificate(&der).unwrap();
+        assert_eq!(cert.subject().to_string(), "CN=example.org, O=Agate, C=DE");
+        let validity = cert.validity();
+        assert_eq!(
+            validity.not_after.timestamp() - validity.not_before.timestamp(),
+            30 * 24 * 60 * 60
+        );
+        let san = cert.subject_alternative_name().unwrap().unwrap();
+        assert_eq!(
+            san.value.general_names,
+            [
+                GeneralName::DNSName("example.org"),
+                GeneralName::DNSName("www.example.com"),
+                GeneralName::IPAddress(&[192, 0, 2, 1]),
+            ]
+        );
+
+        // the IP address is only added for example.org
+        let der = fs::read(certs.join("example.com/cert.der")).unwrap();
+        let (_, cert) = parse_x509_certificate(&der).unwrap();
+        let san = cert.subject_alternative_name().unwrap().unwrap();
+        assert_eq!(san.value.general_names.len(), 2);
+
+        fs::remove_dir_all(certs).unwrap();
+    }
+
+    #[test]
+    /// - expired self signed certificates are replaced
+    /// - the key stays the same
+    fn regenerate_expired() {
+        let certs = certs_dir("regenerate");
+        fs::create_dir(certs.join("example.com")).unwrap();
+        for file in ["cert.der", "key.der"] {
+            fs::copy(
+                concat!(
+                    env!("CARGO_MANIFEST_DIR"),
+                    "/tests/data/regenerate/example.com/"
+                )
+                .to_string()
+                    + file,
+                certs.join("example.com").join(file),
+            )
+            .unwrap();
+        }
+
+        let mut server = Server::new(&[
+            "--certs",
+            certs.to_str().unwrap(),
+            "--hostname",
+            "example.com",
+            "--regenerate-expired",
+        ]);
+        server.stop().unwrap();
+
+        let old = include_bytes!("data/regenerate/example.com/cert.der");
+        let (_, old) = parse_x509_certificate(old).unwrap();
+        let new = fs::read(certs.join("example.com/cert.der")).unwrap();
+        let (_, new) = parse_x509_certificate(&new).unwrap();
+        assert!(new.validity().is_valid());
+        assert_eq!(new.public_key().raw, old.public_key().raw);
+
+        fs::remove_dir_all(certs).unwrap();
+    }
+}
+
+mod timeouts {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn handshake() {
+        let mut server = Server::new(&["--handshake-timeout", "1"]);
+
+        let mut tcp = TcpStream::connect(server.get_addr()).unwrap();
+        tcp.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        // the server closes the connection instead of waiting for the handshake
+        assert_eq!(tcp.read(&mut [0; 10]).unwrap(), 0);
+
+        server.stop().unwrap();
+    }
+
+    #[test]
+    fn request() {
+        let mut server = Server::new(&["--certs", "multicert", "--request-timeout", "1"]);
+
+        let (mut session, mut tcp) = connect(&server);
+        let mut tls = rustls::Stream::new(&mut session, &mut tcp);
+        // send an incomplete request
+        write!(tls, "gemini://example.com/").unwrap();
+
+        // the server closes the connection without a response
+        let mut response = vec![];
+        tls.read_to_end(&mut response).unwrap();
+        assert!(response.is_empty());
+
+        server.stop().unwrap();
+    }
+
+    #[test]
+    /// the server stops sending a file the client does not read
+    fn write() {
+        let content =
+            std::env::temp_dir().join(format!("agate-test-write-timeout-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&content);
+        fs::create_dir(&content).unwrap();
+        // large enough to not fit in the socket buffers
+        let size = 64 * 1024 * 1024;
+        fs::File::create(content.join("large.bin"))
+            .unwrap()
+            .set_len(size)
+            .unwrap();
+
+        let mut server = Server::new(&[
+            "--certs",
+            "multicert",
+            "--content",
+            content.to_str().unwrap(),
+            "--write-timeout",
+            "1",
+        ]);
+
+        let (mut session, mut tcp) = connect(&server);
+        let mut tls = rustls::Stream::new(&mut session, &mut tcp);
+        write!(tls, "gemini://example.com:{}/large.bin\r\n", server.port).unwrap();
+        // stop reading for longer than the timeout
+        sleep(Duration::from_secs(3));
+
+        let mut response = vec![];
+        let _ = tls.read_to_end(&mut response);
+        assert!(response.starts_with(b"20 application/octet-stream\r\n"));
+        assert!((response.len() as u64) < size);
+
+        server.stop().unwrap();
+        fs::remove_dir_all(content).unwrap();
+    }
+}
+
+#[cfg(unix)]
+mod shutdown {
+    use super::*;
+
+    #[test]
+    /// open connections are finished, but new ones are not accepted
+    fn drain() {
+        let mut server = Server::new(&["--certs", "multicert"]);
+
+        let (mut session, mut tcp) = connect(&server);
+        server.terminate();
+        sleep(Duration::from_millis(500));
+        assert!(TcpStream::connect(server.get_addr()).is_err());
+
+        let mut tls = rustls::Stream::new(&mut session, &mut tcp);
+        write!(tls, "gemini://example.com:{}/\r\n", server.port).unwrap();
+        let mut response = String::new();
+        let _ = tls.read_to_string(&mut response);
+        assert!(response.starts_with("20 text/gemini\r\n"), "{response:?}");
+
+        let log = server.wait();
+        assert!(log.contains("All connections finished"));
+    }
+
+    #[test]
+    /// connections that take too long are closed
+    fn grace_period() {
+        let mut server = Server::new(&[
+            "--certs",
+            "multicert",
+            "--request-timeout",
+            "60",
+            "--grace-period",
+            "1",
+        ]);
+
+        // never send a request
+        let _connection = connect(&server);
+        let start = std::time::Instant::now();
+        server.terminate();
+
+        let log = server.wait();
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert!(log.contains("Closing 1 connections"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn remove_socket() {
+        let path = std::env::temp_dir().join(format!("agate-test-shutdown-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut server = Server::new(&["--socket", path.to_str().unwrap()]);
+        // the server may have only logged that the TCP listener started
+        for _ in 0..50 {
+            if path.exists() {
+                break;
+            }
+            sleep(Duration::from_millis(100));
+        }
+        assert!(path.exists());
+
+        server.terminate();
+        server.wait();
+        assert!(!path.exists());
+    }
+}
+
+#[cfg(unix)]
+mod systemd {
+    use super::*;
+    use std::os::fd::OwnedFd;
+    use std::os::unix::net::{UnixDatagram, UnixListener, UnixStream};
+
+    /// Requests the index page over the stream and returns the response header.
+    fn request<S: Read + Write>(stream: &mut S, port: u16) -> String {
+        let mut session = client_session();
+        let mut tls = rustls::Stream::new(&mut session, stream);
+        write!(tls, "gemini://example.com:{port}/\r\n").unwrap();
+        let mut buf = [0; 16];
+        tls.read_exact(&mut buf).unwrap();
+        String::from_utf8_lossy(&buf).into_owned()
+    }
+
+    #[test]
+    /// listens on sockets passed like with systemd socket activation
+    fn socket_activation() {
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let tcp_addr = tcp.local_addr().unwrap();
+        let unix_path =
+            std::env::temp_dir().join(format!("agate-test-activation-{}", std::process::id()));
+        let _ = std::fs::remove_file(&unix_path);
+        let unix = UnixListener::bind(&unix_path).unwrap();
+
+        let mut server = Server::new_with(&["--certs", "multicert"], |command, _| {
+            // pass the sockets as standard input and output, so they get
+            // file descriptors 0 and 1
+            command
+                .stdin(OwnedFd::from(tcp))
+                .stdout(OwnedFd::from(unix))
+                .env("LISTEN_FDS", "2")
+                .env("LISTEN_FDS_FIRST_FD", "0")
+                .env("LISTEN_FDNAMES", "gemini:local");
+        });
+
+        let mut tcp = TcpStream::connect(tcp_addr).unwrap();
+        tcp.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(request(&mut tcp, tcp_addr.port()), "20 text/gemini\r\n");
+
+        let mut unix = UnixStream::connect(&unix_path).unwrap();
+        unix.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(request(&mut unix, DEFAULT_PORT), "20 text/gemini\r\n");
+
+        server.stop().unwrap();
+        // sockets that were passed in are not removed
+        assert!(unix_path.exists());
+        std::fs::remove_file(unix_path).unwrap();
+    }
+
+    #[test]
+    fn notify() {
+        let path = std::env::temp_dir().join(format!("agate-test-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        let mut server = Server::new_with(&[], |command, _| {
+            command.env("NOTIFY_SOCKET", &path);
+        });
+
+        let mut buf = [0; 1024];
+        let len = socket.recv(&mut buf).unwrap();
+        let message = String::from_utf8_lossy(&buf[..len]);
+        assert!(message.lines().any(|line| line == "READY=1"), "{message}");
+
+        server.terminate();
+        let len = socket.recv(&mut buf).unwrap();
+        let message = String::from_utf8_lossy(&buf[..len]);
+        assert!(
+            message.lines().any(|line| line == "STOPPING=1"),
+            "{message}"
+        );
+
+        server.wait();
+        std::fs::remove_file(path).unwrap();
+    }
+}
+
+mod proxy_protocol {
+    use super::*;
+
+    /// Sends the PROXY protocol header and requests the index page for the
+    /// port. Returns the response header.
+    fn request<S: Read + Write>(stream: &mut S, header: &[u8], port: u16) -> String {
+        stream.write_all(header).unwrap();
+        let mut session = client_session();
+        let mut tls = rustls::Stream::new(&mut session, stream);
+        write!(tls, "gemini://example.com:{port}/\r\n").unwrap();
+        let mut response = String::new();
+        let _ = tls.read_to_string(&mut response);
+        response.lines().next().unwrap_or_default().to_string()
+    }
+
+    #[test]
+    /// the client address from the header is used for rate limiting
+    fn v1() {
+        let mut server = Server::new_with(
+            &["--certs", "multicert", "--rate-limit", "1/60"],
+            |command, addr| {
+                command.args(["--proxy-protocol", &addr.to_string()]);
+            },
+        );
+        let port = server.port;
+        let header = |client: &str| format!("PROXY TCP4 {client} 127.0.0.1 40000 {port}\r\n");
+
+        let mut tcp = TcpStream::connect(server.get_addr()).unwrap();
+        tcp.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(
+            request(&mut tcp, header("192.0.2.1").as_bytes(), port),
+            "20 text/gemini"
+        );
+        let mut tcp = TcpStream::connect(server.get_addr()).unwrap();
+        tcp.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(
+            request(&mut tcp, header("192.0.2.2").as_bytes(), port),
+            "20 text/gemini"
+        );
+        let mut tcp = TcpStream::connect(server.get_addr()).unwrap();
+        tcp.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert!(request(&mut tcp, header("192.0.2.1").as_bytes(), port).starts_with("44 "));
+
+        server.stop().unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    /// the destination port from the header is checked
+    fn v2_unix() {
+        let path = std::env::temp_dir().join(format!("agate-test-proxy-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut server = Server::new(&[
+            "--certs",
+            "multicert",
+            "--socket",
+            path.to_str().unwrap(),
+            "--proxy-protocol",
+            path.to_str().unwrap(),
+        ]);
+
+        let mut header = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c".to_vec();
+        header.extend([192, 0, 2, 1, 127, 0, 0, 1]);
+        header.extend(40000_u16.to_be_bytes());
+        header.extend(1970_u16.to_be_bytes());
+
+        let connect = || loop {
+            if let Ok(unix) = std::os::unix::net::UnixStream::connect(&path) {
+                unix.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+                break unix;
+            }
+            sleep(Duration::from_millis(10));
+        };
+        assert_eq!(request(&mut connect(), &header, 1970), "20 text/gemini");
+        assert_eq!(
+            request(&mut connect(), &header, DEFAULT_PORT),
+            "53 Proxy request refused"
+        );
+
+        server.stop().unwrap();
+    }
+
+    #[test]
+    fn missing_header() {
+        let mut server = Server::new_with(&["--certs", "multicert"], |command, addr| {
+            command.args(["--proxy-protocol", &addr.to_string()]);
+        });
+
+        let mut tcp = TcpStream::connect(server.get_addr()).unwrap();
+        tcp.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut session = client_session();
+        let mut tls = rustls::Stream::new(&mut session, &mut tcp);
+        // the client hello is taken as an invalid header
+        assert!(write!(tls, "gemini://example.com:{}/\r\n", server.port).is_err());
+
+        server.stop().unwrap();
+    }
+}
+
+mod access_log {
+    use super::*;
+    use std::fs;
+
+    /// Requests the index page and returns the whole response.
+    fn request(server: &Server) -> Vec<u8> {
+        let (mut session, mut tcp) = connect(server);
+        let mut tls = rustls::Stream::new(&mut session, &mut tcp);
+        write!(tls, "gemini://example.com:{}/\r\n", server.port).unwrap();
+        let mut response = vec![];
+        let _ = tls.read_to_end(&mut response);
+        response
+    }
+
+    /// Waits until the log file has the number of lines and returns them.
+    fn read_lines(path: &std::path::Path, count: usize) -> Vec<String> {
+        for _ in 0..50 {
+            let lines = fs::read_to_string(path)
+                .unwrap_or_default()
+                .lines()
+                .map(str::to_string)
+                .collect::<Vec<_>>();
+            if lines.len() >= count {
+                return lines;
+            }
+            sleep(Duration::from_millis(100));
+        }
+        panic!("log file {} does not have {count} lines", path.display());
+    }
+
+    #[test]
+    fn json() {
+        let path = std::env::temp_dir().join(format!("agate-test-log-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut server = Server::new(&[
+            "--certs",
+            "multicert",
+            "--log-ip",
+            "--access-log",
+            path.to_str().unwrap(),
+            "--access-log-format",
+            "json",
+        ]);
+
+        let response = request(&server);
+        let line = &read_lines(&path, 1)[0];
+        for field in [
+            r#""listener":"127.0.0.1:"#.to_string(),
+            r#""peer":"127.0.0.1""#.to_string(),
+            r#""sni":"example.com""#.to_string(),
+            format!(r#""request":"gemini://example.com:{}/""#, server.port),
+            r#""status":20,"meta":"text/gemini""#.to_string(),
+            format!(r#""bytes_sent":{},"#, response.len()),
+            r#""cert":null,"error":null}"#.to_string(),
+        ] {
+            assert!(line.contains(&field), "{field} missing in {line}");
+        }
+
+        server.stop().unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    /// the log file can be rotated
+    fn reopen() {
+        let path = std::env::temp_dir().join(format!("agate-test-log-{}.log", std::process::id()));
+        let rotated = path.with_extension("log.1");
+        let _ = fs::remove_file(&path);
+        let mut server = Server::new(&[
+            "--certs",
+            "multicert",
+            "--access-log",
+            path.to_str().unwrap(),
+            "--access-log-format",
+            "common",
+        ]);
+
+        request(&server);
+        read_lines(&path, 1);
+        fs::rename(&path, &rotated).unwrap();
+        Command::new("kill")
+            .args(["-HUP", &server.server.id().to_string()])
+            .status()
+            .unwrap();
+        // the file is created again when the signal is handled
+        for _ in 0..50 {
+            if path.exists() {
+                break;
+            }
+            sleep(Duration::from_millis(100));
+        }
+        request(&server);
+
+        let line = &read_lines(&path, 1)[0];
+        assert!(line.starts_with("- - - ["), "unexpected log line {line:?}");
+        assert!(line.ends_with(&format!(
+            "] \"gemini://example.com:{}/\" 20 {}",
+            server.port,
+            b"20 text/gemini\r\n".len() + include_bytes!("data/content/index.gmi").len()
+        )));
+        assert_eq!(read_lines(&rotated, 1).len(), 1);
+
+        server.stop().unwrap();
+        fs::remove_file(path).unwrap();
+        fs::remove_file(rotated).unwrap();
+    }
+}
+
+mod metrics {
+    use super::*;
+
+    /// Sends an HTTP GET request and returns the response.
+    fn http_get(addr: SocketAddr, path: &str) -> String {
+        let mut tcp = TcpStream::connect(addr).unwrap();
+        tcp.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        write!(tcp, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        tcp.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn counters() {
+        let metrics_addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut server = Server::new(&[
+            "--certs",
+            "multicert",
+            "--metrics",
+            &metrics_addr.to_string(),
+        ]);
+
+        // a successful request
+        let (mut session, mut tcp) = connect(&server);
+        let mut tls = rustls::Stream::new(&mut session, &mut tcp);
+        write!(tls, "gemini://example.com:{}/\r\n", server.port).unwrap();
+        let mut response = vec![];
+        let _ = tls.read_to_end(&mut response);
+        // a failed TLS handshake
+        let mut tcp = TcpStream::connect(server.get_addr()).unwrap();
+        tcp.write_all(b"not TLS\r\n").unwrap();
+        let _ = tcp.read_to_end(&mut vec![]);
+
+        let expected = [
+            "agate_connections_total 2\n".to_string(),
+            "agate_tls_handshake_failures_total 1\n".to_string(),
+            "agate_requests_total{status=\"20\"} 1\n".to_string(),
+            format!("agate_sent_bytes_total {}\n", response.len()),
+            "agate_request_duration_seconds_bucket{le=\"+Inf\"} 1\n".to_string(),
+            "agate_request_duration_seconds_count 1\n".to_string(),
+            // the .meta files in the content directory, read at startup
+            "agate_meta_reloads_total 1\n".to_string(),
+        ];
+        // the counters are updated after the response was sent
+        let mut metrics = String::new();
+        for _ in 0..50 {
+            metrics = http_get(metrics_addr, "/metrics");
+            if expected.iter().all(|line| metrics.contains(line)) {
+                break;
+            }
+            sleep(Duration::from_millis(100));
+        }
+        assert!(metrics.starts_with("HTTP/1.1 200 OK\r\n"));
+        for line in expected {
+            assert!(metrics.contains(&line), "{line:?} missing in {metrics}");
+        }
+
+        assert!(http_get(metrics_addr, "/").starts_with("HTTP/1.1 404 "));
+
+        server.stop().unwrap();
+    }
+
+    #[test]
+    /// - metrics are only served on other addresses than loopback addresses
+    ///   if that is allowed explicitly
+    fn public_address() {
+        let output = Command::new(BINARY_PATH)
+            .current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data"))
+            .args(["--metrics", "0.0.0.0:9965"])
+            .output()
+            .expect("failed to start binary");
+        assert!(!output.status.success());
+        let message = String::from_utf8(output.stderr).unwrap();
+        assert!(message.contains("--metrics-public"), "{message}");
+    }
+}
+
+mod config {
+    use super::*;
+
+    /// Runs agate with a configuration file that has errors, returns the
+    /// error message.
+    fn error(config: &str) -> String {
+        let output = Command::new(BINARY_PATH)
+            .current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data"))
+            .args(["--config", config])
+            .output()
+            .expect("failed to start binary");
+        assert!(!output.status.success());
+        String::from_utf8(output.stderr).unwrap()
+    }
+
+    #[test]
+    /// - options are read from the configuration file
+    fn options() {
+        let page = get(
+            &["--config", "config/agate.ini"],
+            "gemini://example.com/page.gmi",
+        )
+        .expect("could not get page");
+
+        assert_eq!(page.status, Status::Success.value());
+        assert_eq!(page.meta, "text/gemini;lang=de");
+        assert_eq!(
+            page.content,
+            include_bytes!("data/content/example.com/page.gmi")
+        );
+    }
+
+    #[test]
+    /// - options on the command line replace the ones in the file
+    fn command_line() {
+        let page = get(
+            &["--config", "config/agate.ini", "--lang", "en"],
+            "gemini://example.com/page.gmi",
+        )
+        .expect("could not get page");
+
+        assert_eq!(page.status, Status::Success.value());
+        assert_eq!(page.meta, "text/gemini;lang=en");
+    }
+
+    #[test]
+    /// - hostnames can have their own content directory and language
+    fn host_section() {
+        let page = get(
+            &["--config", "config/agate.ini"],
+            "gemini://example.org/page.gmi",
+        )
+        .expect("could not get page");
+
+        assert_eq!(page.status, Status::Success.value());
+        assert_eq!(page.meta, "text/gemini;lang=nl");
+        assert_eq!(
+            page.content,
+            include_bytes!("data/config/example.org/page.gmi")
+        );
+    }
+
+    #[test]
+    /// - directories can be listed without a .directory-listing-ok file
+    fn host_directory_listing() {
+        let page = get(&["--config", "config/agate.ini"], "gemini://example.org/")
+            .expect("could not get page");
+
+        assert_eq!(page.status, Status::Success.value());
+        assert_eq!(page.content, b"=> page.gmi\n");
+    }
+
+    #[test]
+    /// - values of options that can only be given once are not split
+    fn single_values() {
+        let page = get(
+            &["--config", "config/single_values.ini"],
+            "gemini://localhost/index.gmi",
+        )
+        .expect("could not get page");
+
+        assert_eq!(page.status, Status::Success.value());
+        assert_eq!(page.meta, "text/gemini;lang=de,nl");
+    }
+
+    #[test]
+    /// - errors point to the offending key
+    fn unknown_key() {
+        let message = error("config/unknown_key.ini");
+        assert!(message.contains("key \"adress\""), "{message}");
+        assert!(message.contains("unknown option"), "{message}");
+    }
+
+    #[test]
+    /// - hosts that are not aliases can not share a content directory
+    fn same_content() {
+        let message = error("config/same_content.ini");
+        assert!(message.contains("same content directory"), "{message}");
+        assert!(message.contains("aliases"), "{message}");
+    }
+
+    #[test]
+    /// - short option names are not accepted
+    fn short_key() {
+        let message = error("config/short_key.ini");
+        assert!(message.contains("key \"V\""), "{message}");
+        assert!(message.contains("unknown option"), "{message}");
+    }
+
+    #[test]
+    fn bad_flag() {
+        let message = error("config/bad_flag.ini");
+        assert!(message.contains("key \"only-tls13\""), "{message}");
+        assert!(message.contains("expected true or false"), "{message}");
+    }
+}
+
+mod rate_limit {
+    use super::*;
+    use std::time::Instant;
+
+    /// Requests the index page from the server, which uses the multicert
+    /// certificates. Returns the first line of the response.
+    fn request(server: &Server) -> String {
+        let (mut session, mut tcp) = connect(server);
+        let mut tls = rustls::Stream::new(&mut session, &mut tcp);
+
+        write!(tls, "gemini://example.com:{}/\r\n", server.port).unwrap();
+        let mut response = String::new();
+        BufReader::new(tls).read_line(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    /// - requests over the limit get status 44 with the seconds to wait
+    fn slow_down() {
+        let mut server = Server::new(&["--certs", "multicert", "--rate-limit", "2/60"]);
+
+        assert!(request(&server).starts_with("20 "));
+        assert!(request(&server).starts_with("20 "));
+        let response = request(&server);
+        let seconds: u64 = response
+            .strip_prefix("44 ")
+            .expect("request was not limited")
+            .trim_end()
+            .parse()
+            .unwrap();
+        assert!((1..=30).contains(&seconds));
+
+        server.stop().unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn unknown_listener() {
+        let mut server = Server::new(&["--rate-limit", "127.0.0.1:1=2/60"]);
+
+        // wait for the server to stop, it should crash
+        let _ = server.server.wait();
+    }
+
+    #[test]
+    /// - further connections wait until an open connection is closed
+    fn max_connections() {
+        let mut server = Server::new(&[
+            "--certs",
+            "multicert",
+            "--max-connections",
+            "1",
+            "--handshake-timeout",
+            "1",
+        ]);
+
+        // occupies the only connection until the handshake times out
+        let _idle = TcpStream::connect(server.get_addr()).unwrap();
+        let start = Instant::now();
+        assert!(request(&server).starts_with("20 "));
+        assert!(start.elapsed() >= Duration::from_millis(500));
+
+        server.stop().unwrap();
+    }
+
+    #[test]
+    /// - a listener at its limit does not use up the global limit
+    fn listener_limit_first() {
+        let other = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut server = Server::new(&[
+            "--certs",
+            "multicert",
+            "--addr",
+            &other.to_string(),
+            "--max-connections",
+            "3",
+            "--max-connections",
+            &format!("{other}=1"),
+            "--handshake-timeout",
+            "1",
+        ]);
+
+        // the first connection occupies the other listener, which then waits
+        // for its own limit
+        let _idle = TcpStream::connect(other).unwrap();
+        sleep(Duration::from_millis(100));
+        // every listener holds a permit while waiting for a connection, so
+        // with the open connections all permits are used unless the other
+        // listener waits without a global permit
+        let _open = connect(&server);
+        let start = Instant::now();
+        assert!(request(&server).starts_with("20 "));
+        assert!(start.elapsed() < Duration::from_millis(500));
+
+        server.stop().unwrap();
+    }
+}
+
+mod titan {
+    use super::*;
+    use std::fs;
+    use trotter::Titan;
+
+    const ALICE: &str = "FF:C2:A9:75:7A:F0:CA:BB:0E:4D:B5:52:A9:17:C0:E8:58:ED:8A:1F:11:7F:36:0F:3A:BA:7B:3C:BF:5F:71:E2";
+
+    /// Creates a content directory that allows uploads and starts a server
+    /// for it with the extra `args`.
+    fn setup(name: &str, args: &[&str]) -> (Server, PathBuf) {
+        let content =
+            std::env::temp_dir().join(format!("agate-test-titan-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&content);
+        fs::create_dir_all(content.join("notes")).unwrap();
+        fs::write(
+            content.join("notes/.meta"),
+            format!(
+                "[titan]\n*.gmi: token:s3cret\n*.txt:\nalice.gmi: {ALICE}\n\
+                drafts/**: token:s3cret\n[cgi]\nscript.gmi:\n"
+            ),
+        )
+        .unwrap();
+
+        let server = Server::new(&[&["--content", content.to_str().unwrap()], args].concat());
+        (server, content)
+    }
+
+    fn upload(server: &Server, actor: Actor, path: &str, token: Option<&str>) -> Response {
+        let actor = actor.proxy("localhost".into(), server.port);
+        let titan = Titan {
+            content: b"# Uploaded\n".to_vec(),
+            mimetype: "text/gemini".into(),
+            token: token.map(Into::into),
+        };
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(actor.upload(format!("titan://localhost:{}{path}", server.port), titan))
+            .expect("could not upload")
+    }
+
+    fn actor_with_cert(cert: &str) -> Actor {
+        let dir = PathBuf::from(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/data/client_certs"
+        ));
+        Actor::default()
+            .cert_file(dir.join(cert).join("cert.pem"))
+            .key_file(dir.join(cert).join("key.pem"))
+    }
+
+    #[test]
+    /// - uploads with a valid token are stored
+    /// - the client is redirected to the uploaded file
+    fn token() {
+        let (mut server, content) = setup("token", &[]);
+
+        let response = upload(&server, Actor::default(), "/notes/new.gmi", Some("s3cret"));
+        assert_eq!(response.status, 30);
+        assert_eq!(
+            response.meta,
+            format!("gemini://localhost:{}/notes/new.gmi", server.port)
+        );
+        assert_eq!(
+            fs::read(content.join("notes/new.gmi")).unwrap(),
+            b"# Uploaded\n"
+        );
+
+        server.stop().unwrap();
+        fs::remove_dir_all(content).unwrap();
+    }
+
+    #[test]
+    /// - uploads with a wrong or missing token are rejected
+    fn wrong_token() {
+        let (mut server, content) = setup("wrong-token", &[]);
+
+        let response = upload(&server, Actor::default(), "/notes/new.gmi", Some("guess"));
+        assert_eq!(response.status, Status::CertificateNotAuthorised.value());
+        let response = upload(&server, Actor::default(), "/notes/new.gmi", None);
+        assert_eq!(response.status, Status::CertificateNotAuthorised.value());
+        assert!(!content.join("notes/new.gmi").exists());
+
+        server.stop().unwrap();
+        fs::remove_dir_all(content).unwrap();
+    }
+
+    #[test]
+    /// - uploads with client certificates are accepted if configured
+    fn client_cert() {
+        let (mut server, content) = setup("client-cert", &[]);
+
+        let response = upload(&server, Actor::default(), "/notes/new.txt", None);
+        assert_eq!(response.status, Status::ClientCertificateRequired.value());
+        let response = upload(&server, actor_with_cert("bob"), "/notes/new.txt", None);
+        assert_eq!(response.status, 30);
+
+        let response = upload(&server, actor_with_cert("bob"), "/notes/alice.gmi", None);
+        assert_eq!(response.status, Status::CertificateNotAuthorised.value());
+        let response = upload(&server, actor_with_cert("alice"), "/notes/alice.gmi", None);
+        assert_eq!(response.status, 30);
+
+        server.stop().unwrap();
+        fs::remove_dir_all(content).unwrap();
+    }
+
+    #[test]
+    /// - uploads are rejected where they are not configured
+    /// - sidecar files, CGI scripts and paths outside the content directory
+    ///   can not be uploaded to
+    fn not_allowed() {
+        let (mut server, content) = setup("not-allowed", &[]);
+
+        for path in [
+            "/index.gmi",
+            "/notes/.meta",
+            "/notes/%2E%2E/x.gmi",
+            "/notes/script.gmi",
+        ] {
+            let response = upload(&server, Actor::default(), path, Some("s3cret"));
+            assert_eq!(response.status, Status::BadRequest.value(), "{path}");
+        }
+        assert!(!content.join("index.gmi").exists());
+        assert!(!content.join("notes/script.gmi").exists());
+        assert!(
+            fs::read_to_string(content.join("notes/.meta"))
+                .unwrap()
+                .starts_with("[titan]")
+        );
+
+        server.stop().unwrap();
+        fs::remove_dir_all(content).unwrap();
+    }
+
+    #[test]
+    /// - nothing can be uploaded if CGI scripts are enabled
+    fn cgi_enabled() {
+        let (mut server, content) = setup("cgi-enabled", &["--cgi"]);
+
+        let response = upload(&server, Actor::default(), "/notes/new.gmi", Some("s3cret"));
+        assert_eq!(response.status, Status::BadRequest.value());
+        assert!(!content.join("notes/new.gmi").exists());
+
+        server.stop().unwrap();
+        fs::remove_dir_all(content).unwrap();
+    }
+
+    #[test]
+    /// - missing directories of an upload are created
+    fn new_directory() {
+        let (mut server, content) = setup("new-directory", &[]);
+
+        let response = upload(
+            &server,
+            Actor::default(),
+            "/notes/drafts/new/x.gmi",
+            Some("s3cret"),
+        );
+        assert_eq!(response.status, 30);
+        assert_eq!(
+            fs::read(content.join("notes/drafts/new/x.gmi")).unwrap(),
+            b"# Uploaded\n"
+        );
+
+        server.stop().unwrap();
+        fs::remove_dir_all(content).unwrap();
+    }
+}
+
+mod directory_listing {
+    use super::*;
+
+    #[test]
+    /// - shows directory listing when enabled
+    /// - shows directory listing preamble correctly
+    /// - encodes link URLs correctly
+    fn with_preamble() {
+        let page = get(&["--content", "dirlist-preamble"], "gemini://localhost/")
+            .expect("could not get page");
+
+        assert_eq!(page.status, Status::Success.value());
+        assert_eq!(page.meta, "text/gemini");
+        assert_eq!(
+            page.content,
+            b"This is a directory listing\n=> a\n=> b\n=> wao%20spaces wao spaces\n"
+        );
+    }
+
+    #[test]
+    fn empty_preamble() {
+        let page =
+            get(&["--content", "dirlist"], "gemini://localhost/").expect("could not get page");
+
+        assert_eq!(page.status, Status::Success.value());
+        assert_eq!(page.meta, "text/gemini");
+        assert_eq!(page.content, b"=> a\n=> b\n");
+    }
+}
+
+mod cache {
+    use super::*;
+
+    #[test]
+    /// - files and directory listings are served from the cache
+    /// - changed files and directories are read again
+    fn invalidation() {
+        let mut temp = TempServer::new(
+            "cache",
+            &[
+                ("page.gmi", "# Old\n"),
+                ("dir/.directory-listing-ok", ""),
+                ("dir/a.txt", "a"),
+            ],
+            &["--cache-size", "4096"],
+        );
+
+        for _ in 0..2 {
+            assert_eq!(temp.get("/page.gmi").content, b"# Old\n");
+            assert_eq!(temp.get("/dir/").content, b"=> a.txt\n");
+        }
+
+        // make sure the modification time changes
+        sleep(Duration::from_millis(50));
+        temp.write("page.gmi", "# New\n");
+        temp.write("dir/b.txt", "b");
+
+        let page = temp.get("/page.gmi");
+        assert_eq!(page.meta, "text/gemini");
+        assert_eq!(page.content, b"# New\n");
+        assert_eq!(temp.get("/dir/").content, b"=> a.txt\n=> b.txt\n");
+
+        #[cfg(unix)]
+        {
+            temp.server.terminate();
+            let log = temp.server.wait();
+            assert!(log.contains("Cache hit for"), "no cache hit in {log}");
+        }
+    }
+
+    #[test]
+    /// - the response header is cached with the file
+    /// - changes to the configuration files are not hidden by the cache
+    fn meta_changes() {
+        let mut temp = TempServer::new(
+            "cache-meta",
+            &[("page.txt", "text"), (".meta", "page.txt: text/x-old\n")],
+            &["--cache-size", "4096"],
+        );
+
+        for _ in 0..2 {
+            assert_eq!(temp.get("/page.txt").meta, "text/x-old");
+        }
+
+        // make sure the modification time changes
+        sleep(Duration::from_millis(50));
+        temp.write(".meta", "page.txt: 52 Gone.\n");
+        let page = temp.get("/page.txt");
+        assert_eq!(page.status, Status::Gone.value());
+        assert_eq!(page.meta, "Gone.");
+
+        sleep(Duration::from_millis(50));
+        temp.write(".meta", "page.txt: text/x-new\n");
+        for _ in 0..2 {
+            assert_eq!(temp.get("/page.txt").meta, "text/x-new");
+        }
+
+        std::fs::remove_file(temp.path(".meta")).unwrap();
+        assert_eq!(temp.get("/page.txt").meta, "text/plain");
+        assert_eq!(temp.get("/page.txt").meta, "text/plain");
+
+        #[cfg(unix)]
+        {
+            temp.server.terminate();
+            let log = temp.server.wait();
+            assert!(log.contains("Cache hit for"), "no cache hit in {log}");
+        }
+    }
+}
+
+#[cfg(unix)]
+mod cgi {
+    use super::*;
+
+    #[test]
+    /// - executable files are run as CGI scripts if enabled
+    /// - the query string is passed to the script
+    fn query() {
+        let page = get(
+            &["--content", "cgi", "--cgi"],
+            "gemini://localhost/hello.sh?search",
+        )
+        .expect("could not get page");
+
+        assert_eq!(page.status, Status::Success.value());
+        assert_eq!(page.meta, "text/plain");
+        assert_eq!(
+            page.content,
+            b"query:search\npath:\nscript:/hello.sh\n".as_slice()
+        );
+    }
+
+    #[test]
+    /// - the rest of the path after the script is passed as PATH_INFO
+    fn path_info() {
+        let page = get(
+            &["--content", "cgi", "--cgi"],
+            "gemini://localhost/hello.sh/some/path",
+        )
+        .expect("could not get page");
+
+        assert_eq!(page.status, Status::Success.value());
+        assert_eq!(
+            page.content,
+            b"query:\npath:/some/path\nscript:/hello.sh\n".as_slice()
+        );
+    }
+
+    #[test]
+    /// - executable files are served as files if CGI is not enabled
+    fn disabled() {
+        let page =
+            get(&["--content", "cgi"], "gemini://localhost/hello.sh").expect("could not get page");
+
+        assert_eq!(page.status, Status::Success.value());
+        assert_eq!(page.content, include_bytes!("data/cgi/hello.sh"));
+    }
+
+    #[test]
+    /// - scripts can be marked as CGI scripts in the configuration file
+    fn marked_in_config() {
+        let page =
+            get(&["--content", "cgi"], "gemini://localhost/marked.sh").expect("could not get page");
+
+        assert_eq!(page.status, Status::Success.value());
+        assert_eq!(page.meta, "text/gemini");
+        assert_eq!(page.content, b"marked as CGI\n".as_slice());
+    }
+
+    #[test]
+    /// - invalid response headers from scripts result in a CGI error
+    fn bad_header() {
+        let page = get(
+            &["--content", "cgi", "--cgi"],
+            "gemini://localhost/bad_header.sh",
+        )
+        .expect("could not get page");
+
+        assert_eq!(page.status, Status::CgiError.value());
+    }
+
+    #[test]
+    /// - scripts that take too long are stopped
+    fn timeout() {
+        let page = get(
+            &["--content", "cgi", "--cgi", "--cgi-timeout", "1"],
+            "gemini://localhost/slow.sh",
+        )
+        .expect("could not get page");
+
+        assert_eq!(page.status, Status::CgiError.value());
+        assert_eq!(page.meta, "CGI script timed out.");
+    }
+}
+
+mod scgi {
+    use super::*;
+    use std::collections::HashMap;
+    use std::net::TcpListener;
+
+    /// Starts an SCGI backend that answers a single request with some of the
+    /// headers it received.
+    fn backend() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(&stream);
+
+            // read the netstring containing the headers
+            let mut len = vec![];
+            reader.read_until(b':', &mut len).unwrap();
+            let len: usize = std::str::from_utf8(&len[..len.len() - 1])
+                .unwrap()
+                .parse()
+                .unwrap();
+            let mut headers = vec![0; len + 1];
+            reader.read_exact(&mut headers).unwrap();
+            assert_eq!(headers.pop(), Some(b','));
+
+            let headers = String::from_utf8(headers).unwrap();
+            let mut parts = headers.split('\0');
+            let mut headers = HashMap::new();
+            while let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+                headers.insert(name.to_string(), value.to_string());
+            }
+
+            write!(
+                &stream,
+                "20 text/plain\r\nscgi:{}\nscript:{}\npath:{}\n",
+                headers["SCGI"], headers["SCRIPT_NAME"], headers["PATH_INFO"]
+            )
+            .unwrap();
+        });
+
+        addr
+    }
+
+    #[test]
+    /// - requests below the configured prefix are forwarded to the backend
+    /// - the prefix and rest of the path are passed to the backend
+    /// - the response of the backend is relayed to the client
+    fn forward() {
+        let route = format!("/app=127.0.0.1:{}", backend().port());
+        let page = get(&["--scgi", &route], "gemini://localhost/app/some%20path")
+            .expect("could not get page");
+
+        assert_eq!(page.status, Status::Success.value());
+        assert_eq!(page.meta, "text/plain");
+        assert_eq!(
+            page.content,
+            b"scgi:1\nscript:/app\npath:/some path\n".as_slice()
+        );
+    }
+
+    #[test]
+    /// - paths that only share a prefix string are not forwarded
+    fn other_path() {
+        let route = format!("/test=127.0.0.1:{}", backend().port());
+        let page =
+            get(&["--scgi", &route], "gemini://localhost/test.gmi").expect("could not get page");
+
+        assert_eq!(page.status, Status::Success.value());
+        assert_eq!(page.content, include_bytes!("data/content/test.gmi"));
+    }
+
+    #[test]
+    /// - routes can be limited to a hostname
+    fn hostname() {
+        let route = format!("example.org/app=127.0.0.1:{}", backend().port());
+        let page = get(&["--scgi", &route], "gemini://localhost/app").expect("could not get page");
+        assert_eq!(page.status, Status::NotFound.value());
+
+        let route = format!("localhost/app=127.0.0.1:{}", backend().port());
+        let page = get(&["--scgi", &route], "gemini://localhost/app").expect("could not get page");
+        assert_eq!(page.status, Status::Success.value());
+        assert_eq!(page.content, b"scgi:1\nscript:/app\npath:\n".as_slice());
+    }
+
+    #[test]
+    /// - client certificate entries in configuration files apply to
+    ///   forwarded paths
+    fn client_certs() {
+        let route = format!("/members=127.0.0.1:{}", backend().port());
+        let page =
+            get(&["--scgi", &route], "gemini://localhost/members/app").expect("could not get page");
+        assert_eq!(page.status, Status::ClientCertificateRequired.value());
+
+        let route = format!("/members=127.0.0.1:{}", backend().port());
+        let page = get_with_cert(&["--scgi", &route], "gemini://localhost/members/app", "bob")
+            .expect("could not get page");
+        assert_eq!(page.status, Status::Success.value());
+        assert_eq!(
+            page.content,
+            b"scgi:1\nscript:/members\npath:/app\n".as_slice()
+        );
+    }
+
+    #[test]
+    /// - status for an unreachable backend is "CGI error"
+    fn unreachable() {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let route = format!("/app=127.0.0.1:{port}");
+        let page = get(&["--scgi", &route], "gemini://localhost/app").expect("could not get page");
+
+        assert_eq!(page.status, Status::CgiError.value());
+    }
+}
+
+mod client_certs {
+    use super::*;
+
+    #[test]
+    /// - status for a missing certificate is "client certificate required"
+    fn missing() {
+        let page = get(&[], "gemini://localhost/private.gmi").expect("could not get page");
+
+        assert_eq!(page.status, Status::ClientCertificateRequired.value());
+    }
+
+    #[test]
+    /// - any self signed certificate is accepted if no fingerprints are listed
+    fn any() {
+        let page = get_with_cert(&[], "gemini://localhost/private.gmi", "bob")
+            .expect("could not get page");
+
+        assert_eq!(page.status, Status::Success.value());
+        assert_eq!(page.content, include_bytes!("data/content/private.gmi"));
+    }
+
+    #[test]
+    /// - certificates with a listed fingerprint are accepted
+    fn authorised() {
+        let page = get_with_cert(&[], "gemini://localhost/restricted.gmi", "alice")
+            .expect("could not get page");
+
+        assert_eq!(page.status, Status::Success.value());
+    }
+
+    #[test]
+    /// - status for other certificates is "certificate not authorised"
+    fn not_authorised() {
+        let page = get_with_cert(&[], "gemini://localhost/restricted.gmi", "bob")
+            .expect("could not get page");
+
+        assert_eq!(page.status, Status::CertificateNotAuthorised.value());
+    }
+
+    /// Records whether the server asked for a client certificate, without
+    /// sending one.
+    #[derive(Debug, Default)]
+    struct Asked(std::sync::atomic::AtomicBool);
+
+    impl rustls::client::ResolvesClientCert for Asked {
+        fn resolve(
+            &self,
+            _root_hint_subjects: &[&[u8]],
+            _sigschemes: &[rustls::SignatureScheme],
+        ) -> Option<std::sync::Arc<rustls::sign::CertifiedKey>> {
+            self.0.store(true, Ordering::SeqCst);
+            None
+        }
+
+        fn has_certs(&self) -> bool {
+            true
+        }
+    }
+
+    /// Requests `path` from a server using the multicert certificates.
+    /// Returns whether it asked for a client certificate during the handshake.
+    fn asks_for_cert(server: &Server, path: &str) -> bool {
+        let mut certs = RootCertStore::empty();
+        certs
+            .add(CertificateDer::from(
+                include_bytes!("data/multicert/example.com/cert.der").as_slice(),
+            ))
+            .unwrap();
+        let asked = std::sync::Arc::new(Asked::default());
+        let config = rustls::ClientConfig::builder()
+            .with_root_certificates(certs)
+            .with_client_cert_resolver(asked.clone());
+        let mut session = ClientConnection::new(
+            std::sync::Arc::new(config),
+            "example.com".try_into().unwrap(),
+        )
+        .unwrap();
+        let mut tcp = TcpStream::connect(server.get_addr()).unwrap();
+        tcp.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        while session.is_handshaking() {
+            session.complete_io(&mut tcp).unwrap();
+        }
+
+        let mut tls = rustls::Stream::new(&mut session, &mut tcp);
+        write!(tls, "gemini://example.com:{}{path}\r\n", server.port).unwrap();
+        let mut response = String::new();
+        BufReader::new(tls).read_line(&mut response).unwrap();
+        asked.0.load(Ordering::SeqCst)
+    }
+
+    #[test]
+    /// - clients are only asked for certificates if something uses them
+    /// - client certificate entries are noticed when the configuration file
+    ///   is first used
+    fn only_asked_when_used() {
+        let server = Server::new(&["--certs", "multicert", "--content", "dirlist"]);
+        assert!(!asks_for_cert(&server, "/"));
+        assert!(!asks_for_cert(&server, "/"));
+
+        let server = Server::new(&["--certs", "multicert", "--content", "dirlist", "--cgi"]);
+        assert!(asks_for_cert(&server, "/"));
+
+        // the configuration file has client certificate entries
+        let server = Server::new(&["--certs", "multicert"]);
+        assert!(!asks_for_cert(&server, "/private.gmi"));
+        assert!(asks_for_cert(&server, "/private.gmi"));
+    }
+
+    #[test]
+    /// - `**` entries protect the listing of their base directory too
+    fn directory_listing() {
+        let page = get(&[], "gemini://localhost/members/").expect("could not get page");
+        assert_eq!(page.status, Status::ClientCertificateRequired.value());
+
+        let page = get(&[], "gemini://localhost/members/page.gmi").expect("could not get page");
+        assert_eq!(page.status, Status::ClientCertificateRequired.value());
+
+        let page =
+            get_with_cert(&[], "gemini://localhost/members/", "bob").expect("could not get page");
+        assert_eq!(page.status, Status::Success.value());
+        assert_eq!(page.content, b"=> page.gmi\n");
+    }
+
+    #[test]
+    /// - status for an expired certificate is "certificate not valid"
+    fn expired() {
+        let page = get_with_cert(&[], "gemini://localhost/private.gmi", "expired")
+            .expect("could not get page");
+
+        assert_eq!(page.status, Status::CertificateNotValid.value());
+    }
+}
+
+#[cfg(unix)]
+mod cert_reload {
+    use super::*;
+    use std::fs;
+
+    /// Requests the index page from the server using the given certificate as
+    /// the only trusted one. Returns the first line of the response.
+    fn request(server: &Server, domain: &str) -> Result<String, String> {
+        let mut certs = RootCertStore::empty();
+        let cert = fs::read(format!(
+            "{}/tests/data/multicert/{domain}/cert.der",
+            env!("CARGO_MANIFEST_DIR")
+        ))
+        .unwrap();
+        certs.add(CertificateDer::from(cert)).unwrap();
+        let config = rustls::ClientConfig::builder()
+            .with_root_certificates(certs)
+            .with_no_client_auth();
+
+        let mut session = ClientConnection::new(
+            std::sync::Arc::new(config),
+            domain.to_string().try_into().unwrap(),
+        )
+        .unwrap();
+        let mut tcp = TcpStream::connect(server.get_addr()).unwrap();
+        let mut tls = rustls::Stream::new(&mut session, &mut tcp);
+
+        write!(tls, "gemini://{domain}:{}/\r\n", server.port).map_err(|e| e.to_string())?;
+        let mut response = String::new();
+        BufReader::new(tls)
+            .read_line(&mut response)
+            .map_err(|e| e.to_string())?;
+        Ok(response)
+    }
+
+    fn hangup(server: &Server) {
+        let status = Command::new("kill")
+            .args(["-HUP", &server.server.id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        // give the server some time to reload
+        sleep(Duration::from_millis(500));
+    }
+
+    #[test]
+    /// - certificates are reloaded on SIGHUP
+    /// - if the new certificates can not be loaded, the old ones are kept
+    fn sighup() {
+        let data = PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/multicert"));
+        let certs = std::env::temp_dir().join(format!("agate-test-reload-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&certs);
+        for domain in ["example.com", "example.org"] {
+            fs::create_dir_all(certs.join(domain)).unwrap();
+            fs::copy(
+                data.join("example.com/cert.der"),
+                certs.join(domain).join("cert.der"),
+            )
+            .unwrap();
+            fs::copy(
+                data.join("example.com/key.der"),
+                certs.join(domain).join("key.der"),
+            )
+            .unwrap();
+        }
+
+        let server = Server::new(&["--certs", certs.to_str().unwrap()]);
+        assert_eq!(
+            request(&server, "example.com").unwrap(),
+            "20 text/gemini\r\n"
+        );
+        // the wrong certificate is used for example.org
+        assert!(request(&server, "example.org").is_err());
+
+        // a key that does not match the certificate can not be loaded
+        fs::copy(
+            data.join("example.org/cert.der"),
+            certs.join("example.org/cert.der"),
+        )
+        .unwrap();
+        hangup(&server);
+        assert_eq!(
+            request(&server, "example.com").unwrap(),
+            "20 text/gemini\r\n"
+        );
+        assert!(request(&server, "example.org").is_err());
+
+        fs::copy(
+            data.join("example.org/key.der"),
+            certs.join("example.org/key.der"),
+        )
+        .unwrap();
+        hangup(&server);
+        assert_eq!(
+            request(&server, "example.com").unwrap(),
+            "20 text/gemini\r\n"
+        );
+        assert_eq!(
+            request(&server, "example.org").unwrap(),
+            "20 text/gemini\r\n"
+        );
+
+        fs::remove_dir_all(&certs).unwrap();
+    }
+
+    #[test]
+    /// - certificates replaced on disk are picked up without a signal
+    fn polling() {
+        let data = PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/multicert"));
+        let certs =
+            std::env::temp_dir().join(format!("agate-test-reload-poll-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&certs);
+        for domain in ["example.com", "example.org"] {
+            fs::create_dir_all(certs.join(domain)).unwrap();
+            for file in ["cert.der", "key.der"] {
+                fs::copy(
+                    data.join("example.com").join(file),
+                    certs.join(domain).join(file),
+                )
+                .unwrap();
+            }
+        }
+
+        let server = Server::new(&["--certs", certs.to_str().unwrap()]);
+        assert!(request(&server, "example.org").is_err());
+
+        for file in ["cert.der", "key.der"] {
+            fs::copy(
+                data.join("example.org").join(file),
+                certs.join("example.org").join(file),
+            )
+            .unwrap();
+        }
+        // the certificate directory is checked every 10 seconds
+        let mut response = request(&server, "example.org");
+        for _ in 0..30 {
+            if response.is_ok() {
+                break;
+            }
+            sleep(Duration::from_secs(1));
+            response = request(&server, "example.org");
+        }
+        assert_eq!(response.unwrap(), "20 text/gemini\r\n");
+        assert_eq!(
+            request(&server, "example.com").unwrap(),
+            "20 text/gemini\r\n"
+        );
+
+        fs::remove_dir_all(&certs).unwrap();
     }
 }
@@ -60,7 +60,7 @@ fi
 set -e
 
 echo "copying config files..."
-cp gemini.service /etc/systemd/system/
+cp gemini.service gemini.socket /etc/systemd/system/
 cp gemini.conf /etc/rsyslog.d/
 cp geminilogs /etc/logrotate.d/
 
@@ -72,8 +72,8 @@ mkdir -p /srv/gemini/.certificates
 echo "starting service..."
 systemctl daemon-reload
 systemctl restart rsyslog
-systemctl enable gemini
-systemctl start gemini
+systemctl enable gemini.socket gemini
+systemctl start gemini.socket gemini
 
 echo "setup done, checking..."
 # wait until the restarts would have timed out
@@ -7,11 +7,11 @@
 # the Apache license respectively.
 
 echo "stopping and disabling service..."
-systemctl stop gemini
-systemctl disable gemini
+systemctl stop gemini gemini.socket
+systemctl disable gemini gemini.socket
 
 echo "removing config files..."
-rm -f /etc/systemd/system/gemini.service /etc/rsyslog.d/gemini.conf /etc/logrotate.d/geminilogs
+rm -f /etc/systemd/system/gemini.service /etc/systemd/system/gemini.socket /etc/rsyslog.d/gemini.conf /etc/logrotate.d/geminilogs
 
 echo "deleting certificates..."
 rm -rf /srv/gemini/.certificates